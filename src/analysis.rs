@@ -0,0 +1,534 @@
+use std::ops::Range;
+
+use nom_locate::LocatedSpan;
+
+use crate::{
+    ast::{Expr, Goal, PredicateId, PredicateObj, Statement, USER_MODULE},
+    builtins::Builtin,
+    env::Environment,
+    error::{Error, ErrorKind, WarningKind},
+    parser::{parse_program_located, Syntax},
+    prelude::load_prelude,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+///
+/// A problem found in a source file. The range is in bytes.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub range: Range<usize>,
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(range: Range<usize>, kind: &ErrorKind) -> Self {
+        Diagnostic {
+            range,
+            severity: Severity::Error,
+            code: kind.code(),
+            message: kind.to_string(),
+        }
+    }
+
+    fn warning(range: Range<usize>, kind: &WarningKind) -> Self {
+        Diagnostic {
+            range,
+            severity: Severity::Warning,
+            code: kind.code(),
+            message: kind.to_string(),
+        }
+    }
+}
+
+///
+/// A candidate offered to complete the word being typed.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub label: String,
+    pub detail: String,
+}
+
+///
+/// A well-formed statement with the byte range where it is written
+/// and the module which it belongs to.
+///
+struct LocatedStatement {
+    range: Range<usize>,
+    module: String,
+    stmt: Statement,
+}
+
+impl LocatedStatement {
+    ///
+    /// Returns the predicate objects written in the statement, including the head of a clause.
+    ///
+    fn predicates(&self) -> Vec<&PredicateObj> {
+        match &self.stmt {
+            Statement::Def(stmt) => std::iter::once(&stmt.conclusion)
+                .chain(stmt.premises.iter().flat_map(Goal::predicates))
+                .collect(),
+            Statement::Query(stmt) => vec![&stmt.query],
+            Statement::Test(test) => vec![&test.query],
+            Statement::Directive(_) => vec![],
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+///
+/// Returns the offset where the name at the end of the text starts.
+///
+fn word_start(text: &str) -> usize {
+    text.char_indices()
+        .rev()
+        .find(|(_, c)| !is_name_char(*c))
+        .map_or(0, |(idx, c)| idx + c.len_utf8())
+}
+
+///
+/// Finds the first occurrence of the word in the range, skipping comments
+/// and the occurrences which are a part of a longer name or a variable.
+///
+fn find_word(source: &str, range: Range<usize>, word: &str) -> Option<Range<usize>> {
+    let text = &source[range.clone()];
+    let mut in_comment = false;
+    for (idx, c) in text.char_indices() {
+        match c {
+            '%' => in_comment = true,
+            '\n' => in_comment = false,
+            _ if in_comment || !text[idx..].starts_with(word) => {}
+            _ => {
+                let before = text[..idx].chars().next_back();
+                let after = text[idx + word.len()..].chars().next();
+                let is_boundary = |c: Option<char>| !c.is_some_and(|c| is_name_char(c) || c == '$');
+                if is_boundary(before) && is_boundary(after) {
+                    let start = range.start + idx;
+                    return Some(start..start + word.len());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn collect_var_names<'a>(expr: &'a Expr, names: &mut Vec<&'a str>) {
+    match expr {
+        Expr::Atom(atom) => {
+            for arg in atom.arguments.iter() {
+                collect_var_names(arg, names);
+            }
+        }
+        Expr::Var(var) => names.push(&var.name),
+    }
+}
+
+///
+/// A source file analyzed for editors.
+/// Its definitions are loaded into an environment together with the prelude,
+/// but its queries are never run.
+///
+pub struct Document {
+    source: String,
+    statements: Vec<LocatedStatement>,
+    env: Environment,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    pub fn new(source: &str, syntax: Syntax) -> Self {
+        let mut env = Environment::new();
+        // The prelude is well-formed, which its tests check.
+        let _ = load_prelude(&mut env);
+
+        let (statements, errors) = parse_program_located(LocatedSpan::new(source), syntax);
+        let mut diagnostics = errors
+            .iter()
+            .map(|err| Diagnostic::error(Self::error_range(source, err), err.kind()))
+            .collect::<Vec<_>>();
+
+        let mut module = USER_MODULE.to_string();
+        let mut located = Vec::new();
+        for (range, stmt) in statements {
+            let result = match &stmt {
+                Statement::Def(def) => env.define(&module, def.clone()),
+                Statement::Directive(directive) => {
+                    env.apply_directive(&mut module, directive.clone())
+                }
+                Statement::Query(_) | Statement::Test(_) => Ok(()),
+            };
+            if let Err(kind) = result {
+                diagnostics.push(Diagnostic::error(range.clone(), &kind));
+            }
+            located.push(LocatedStatement {
+                range,
+                module: module.to_string(),
+                stmt,
+            });
+        }
+
+        let mut doc = Document {
+            source: source.to_string(),
+            statements: located,
+            env,
+            diagnostics,
+        };
+        doc.diagnostics.extend(doc.check_calls());
+        doc.diagnostics.extend(doc.check_singletons());
+        doc.diagnostics
+            .sort_by_key(|diagnostic| diagnostic.range.start);
+        doc
+    }
+
+    ///
+    /// Returns the range of the text where a syntax error is found.
+    ///
+    fn error_range(source: &str, err: &Error) -> Range<usize> {
+        let start = err.span().map_or(source.len(), |span| span.offset);
+        let len = match err.kind() {
+            ErrorKind::Parser(found, _) => found.len().max(1),
+            _ => 1,
+        };
+        start..(start + len).min(source.len())
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    ///
+    /// Reports the goals calling predicates with no clauses,
+    /// which are arity mismatches if the predicate is defined with another arity.
+    ///
+    fn check_calls(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for located in &self.statements {
+            let (head, goals) = match &located.stmt {
+                Statement::Def(stmt) => (
+                    Some(self.qualified(&located.module, &stmt.conclusion)),
+                    stmt.premises.iter().flat_map(Goal::predicates).collect(),
+                ),
+                Statement::Query(stmt) => (None, vec![&stmt.query]),
+                Statement::Test(test) => (None, vec![&test.query]),
+                Statement::Directive(_) => continue,
+            };
+            // The goals follow `<-` or `?`.
+            let body_start = match head {
+                Some(_) => find_word(&self.source, located.range.clone(), "<-"),
+                None => None,
+            }
+            .map_or(located.range.start, |range| range.end);
+
+            let mut reported = Vec::new();
+            for goal in goals {
+                let id = self.env.resolve(&self.qualified(&located.module, goal));
+                if self.env.is_defined(&id) || reported.contains(&id) {
+                    continue;
+                }
+                let range = find_word(&self.source, body_start..located.range.end, &goal.name)
+                    .unwrap_or_else(|| located.range.clone());
+
+                let other = self
+                    .env
+                    .predicate_ids()
+                    .into_iter()
+                    .find(|other| other.name == id.name && self.env.is_defined(other));
+                let diagnostic = match (other, &head) {
+                    (Some(other), _) => Diagnostic::error(
+                        range,
+                        &ErrorKind::ArityMismatch(id.name.to_string(), other.arity, id.arity),
+                    ),
+                    (None, Some(head)) => Diagnostic::warning(
+                        range,
+                        &WarningKind::UndefinedPremise(head.clone(), id.clone()),
+                    ),
+                    (None, None) => {
+                        Diagnostic::warning(range, &WarningKind::UnknownPredicate(id.clone()))
+                    }
+                };
+                diagnostics.push(diagnostic);
+                reported.push(id);
+            }
+        }
+        diagnostics
+    }
+
+    ///
+    /// Reports the variables which appear only once in a clause.
+    /// Variables whose names start with `_` are meant to be so.
+    ///
+    fn check_singletons(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for located in &self.statements {
+            let Statement::Def(_) = &located.stmt else {
+                continue;
+            };
+            let mut names = Vec::new();
+            for pred in located.predicates() {
+                for arg in &pred.arguments {
+                    collect_var_names(arg, &mut names);
+                }
+            }
+            for name in &names {
+                let count = names.iter().filter(|other| *other == name).count();
+                if count > 1 || name.starts_with('_') {
+                    continue;
+                }
+                let range = find_word(&self.source, located.range.clone(), &format!("${}", name))
+                    .unwrap_or_else(|| located.range.clone());
+                diagnostics.push(Diagnostic::warning(
+                    range,
+                    &WarningKind::SingletonVariable(name.to_string()),
+                ));
+            }
+        }
+        diagnostics
+    }
+
+    ///
+    /// Returns the id which the predicate object written in the module refers to.
+    ///
+    fn qualified(&self, module: &str, pred_obj: &PredicateObj) -> PredicateId {
+        let mut pred_obj = pred_obj.clone();
+        // A private predicate is reported when the clause is defined.
+        let _ = self.env.qualify(module, &mut pred_obj);
+        pred_obj.id()
+    }
+
+    fn statement_at(&self, offset: usize) -> Option<&LocatedStatement> {
+        self.statements
+            .iter()
+            .find(|located| located.range.start <= offset && offset <= located.range.end)
+    }
+
+    ///
+    /// Returns the name under the cursor, unless it is a variable.
+    ///
+    fn name_at(&self, offset: usize) -> Option<&str> {
+        let offset = offset.min(self.source.len());
+        let start = word_start(&self.source[..offset]);
+        let end = self.source[offset..]
+            .find(|c| !is_name_char(c))
+            .map_or(self.source.len(), |idx| offset + idx);
+        if start == end || self.source[..start].ends_with('$') {
+            return None;
+        }
+        Some(&self.source[start..end])
+    }
+
+    ///
+    /// Finds the predicates which the name under the cursor refers to.
+    /// A name written as a goal or a head refers to that predicate,
+    /// and any other name, such as a closure passed to `call/N`, to all the predicates of the name.
+    ///
+    fn predicates_at(&self, offset: usize) -> Vec<PredicateId> {
+        let Some(name) = self.name_at(offset) else {
+            return vec![];
+        };
+        let module = self
+            .statement_at(offset)
+            .map_or(USER_MODULE, |located| &located.module);
+
+        let mut ids = self
+            .statement_at(offset)
+            .map(|located| located.predicates())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|pred| pred.name == name)
+            .map(|pred| self.env.resolve(&self.qualified(module, pred)))
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            let local_name = PredicateId::new(name.to_string(), 0)
+                .split_module()
+                .1
+                .to_string();
+            ids = self
+                .env
+                .predicate_ids()
+                .into_iter()
+                .filter(|id| id.split_module().1 == local_name && self.env.is_defined(id))
+                .cloned()
+                .collect();
+        }
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    ///
+    /// Returns the ranges of the clauses which define the predicate under the cursor.
+    ///
+    pub fn definition(&self, offset: usize) -> Vec<Range<usize>> {
+        let ids = self.predicates_at(offset);
+        self.statements
+            .iter()
+            .filter(|located| match &located.stmt {
+                Statement::Def(stmt) => {
+                    ids.contains(&self.qualified(&located.module, &stmt.conclusion))
+                }
+                _ => false,
+            })
+            .map(|located| located.range.clone())
+            .collect()
+    }
+
+    ///
+    /// Describes the predicate under the cursor by its arity and the number of its clauses.
+    ///
+    pub fn hover(&self, offset: usize) -> Option<String> {
+        let lines = self
+            .predicates_at(offset)
+            .iter()
+            .map(|id| format!("`{}`: {}", id, self.describe(id)))
+            .collect::<Vec<_>>();
+        (!lines.is_empty()).then(|| lines.join("\n\n"))
+    }
+
+    fn describe(&self, id: &PredicateId) -> String {
+        if Builtin::lookup(id).is_some() {
+            return "built-in".to_string();
+        }
+        let num_clauses = self.env.get_rules(id).map_or(0, |rules| rules.len());
+        let plural = if num_clauses == 1 { "" } else { "s" };
+        let library = if self.env.is_library(id) {
+            ", library"
+        } else {
+            ""
+        };
+        format!("{} clause{}{}", num_clauses, plural, library)
+    }
+
+    ///
+    /// Lists the candidates for the word before the cursor, which are
+    /// the variables of the statement after `$`, and the defined predicates otherwise.
+    ///
+    pub fn completions(&self, offset: usize) -> Vec<Completion> {
+        let offset = offset.min(self.source.len());
+        let start = word_start(&self.source[..offset]);
+        let word = &self.source[start..offset];
+
+        if self.source[..start].ends_with('$') {
+            // The statement may be incomplete, so the variables are taken from the text.
+            let statement_start = self.source[..start].rfind('.').map_or(0, |idx| idx + 1);
+            let mut names = self.source[statement_start..start]
+                .split('$')
+                .skip(1)
+                .map(|rest| rest.split(|c| !is_name_char(c)).next().unwrap_or_default())
+                .filter(|name| !name.is_empty() && name.starts_with(word))
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            return names
+                .into_iter()
+                .map(|name| Completion {
+                    label: name.to_string(),
+                    detail: "variable".to_string(),
+                })
+                .collect();
+        }
+
+        self.env
+            .predicate_ids()
+            .into_iter()
+            .filter(|id| id.name.starts_with(word) && self.env.is_defined(id))
+            .map(|id| Completion {
+                label: id.name.to_string(),
+                detail: format!("{} ({})", id, self.describe(id)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROGRAM: &str = "\
+odd(s($n)) <- even($n).
+even(z).
+even(s($n)) <- odd($n).
+twice($x, $y) <- add($x, $x).
+bad($x, $y) <- undefined($x). % here
+?odd($m).
+";
+
+    #[test]
+    fn diagnostics_test() {
+        let doc = Document::new(PROGRAM, Syntax::Terminated);
+        let found = doc
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| (&PROGRAM[diagnostic.range.clone()], diagnostic.code))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("$y", "W0003"),
+                ("add", "E0002"),
+                ("$y", "W0003"),
+                ("undefined", "W0001"),
+            ]
+        );
+
+        let doc = Document::new("num(z).\nnum(s($n) <- num($n).", Syntax::Terminated);
+        assert_eq!(doc.diagnostics().len(), 1);
+        assert_eq!(doc.diagnostics()[0].code, "E0001");
+        assert_eq!(doc.diagnostics()[0].range.start, 18);
+    }
+
+    #[test]
+    fn navigation_test() {
+        let doc = Document::new(PROGRAM, Syntax::Terminated);
+        // `even` in the first clause.
+        let definitions = doc
+            .definition(15)
+            .into_iter()
+            .map(|range| &PROGRAM[range])
+            .collect::<Vec<_>>();
+        assert_eq!(definitions, vec!["even(z).", "even(s($n)) <- odd($n)."]);
+        assert_eq!(doc.hover(15), Some("`even/1`: 2 clauses".to_string()));
+        assert_eq!(doc.hover(7), None);
+
+        let add = PROGRAM.find("add").unwrap();
+        assert_eq!(doc.hover(add), Some("`add/2`: 0 clauses".to_string()));
+
+        let labels = doc
+            .completions(PROGRAM.find("dd(s").unwrap())
+            .into_iter()
+            .map(|completion| completion.label)
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["odd"]);
+        let vars = doc
+            .completions(PROGRAM.find("$x)").unwrap() + 1)
+            .into_iter()
+            .map(|completion| completion.label)
+            .collect::<Vec<_>>();
+        assert_eq!(vars, vec!["x", "y"]);
+
+        // A name after a character of several bytes.
+        let source = "even(z).\n?«ev";
+        let doc = Document::new(source, Syntax::Terminated);
+        assert_eq!(doc.hover(source.len()), None);
+        let labels = doc
+            .completions(source.len())
+            .into_iter()
+            .map(|completion| completion.label)
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["even"]);
+    }
+}
@@ -1,19 +1,32 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashSet,
+    fmt,
+    hash::{Hash, Hasher},
+    ptr,
+    rc::Rc,
+    str::FromStr,
+};
+
+use crate::{builtins::atom_to_int, symbol::Symbol};
+
 pub type VarID = u32;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DefStatement {
     pub conclusion: PredicateObj,
-    pub premises: Vec<PredicateObj>,
+    pub premises: Vec<Goal>,
 }
 
 impl PartialEq for DefStatement {
     fn eq(&self, other: &Self) -> bool {
-        self.conclusion == other.conclusion || self.premises == other.premises
+        self.conclusion == other.conclusion && self.premises == other.premises
     }
 }
 
 impl DefStatement {
-    pub fn new(conclusion: PredicateObj, premises: Vec<PredicateObj>) -> Statement {
+    pub fn new(conclusion: PredicateObj, premises: Vec<Goal>) -> Statement {
         Statement::Def(DefStatement {
             conclusion,
             premises,
@@ -21,6 +34,21 @@ impl DefStatement {
     }
 }
 
+impl fmt::Display for DefStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.premises.is_empty() {
+            write!(f, "{}", self.conclusion)
+        } else {
+            let premises = self
+                .premises
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>();
+            write!(f, "{} <- {}", self.conclusion, premises.join(", "))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct QueryStatement {
     pub query: PredicateObj,
@@ -38,45 +66,427 @@ impl QueryStatement {
     }
 }
 
+///
+/// Declarations which start with `#` and affect how the following statements are loaded.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    /// `#module name.` puts the following definitions into the module.
+    Module(String),
+    /// `#export name/arity, ....` makes the predicates of the current module visible outside.
+    Export(Vec<PredicateId>),
+    /// `#import name.` makes the exported predicates of the module callable without qualification.
+    Import(String),
+    /// `#dynamic name/arity, ....` declares that the predicates of the current module are modified at runtime.
+    Dynamic(Vec<PredicateId>),
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Directive::Module(name) => write!(f, "#module {}", name),
+            Directive::Export(ids) => {
+                let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+                write!(f, "#export {}", ids.join(", "))
+            }
+            Directive::Import(name) => write!(f, "#import {}", name),
+            Directive::Dynamic(ids) => {
+                let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+                write!(f, "#dynamic {}", ids.join(", "))
+            }
+        }
+    }
+}
+
+///
+/// `#test ?query => [$x = a, ...]; ....` declares the answers which the query is expected to give.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestStatement {
+    pub query: PredicateObj,
+    /// The bindings of each answer. Empty if the query is expected to fail, which is written as `no`.
+    pub answers: Vec<Vec<(String, Expr)>>,
+    /// Whether the answers must come in the written order, which `#test unordered` relaxes.
+    pub ordered: bool,
+}
+
+impl TestStatement {
+    pub fn new(query: PredicateObj, answers: Vec<Vec<(String, Expr)>>, ordered: bool) -> Statement {
+        Statement::Test(TestStatement {
+            query,
+            answers,
+            ordered,
+        })
+    }
+}
+
+impl fmt::Display for TestStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#test ")?;
+        if !self.ordered {
+            write!(f, "unordered ")?;
+        }
+        write!(f, "?{} => ", self.query)?;
+        if self.answers.is_empty() {
+            return write!(f, "no");
+        }
+        let answers = self
+            .answers
+            .iter()
+            .map(|bindings| {
+                let bindings = bindings
+                    .iter()
+                    .map(|(name, expr)| format!("${} = {}", name, expr))
+                    .collect::<Vec<_>>();
+                format!("[{}]", bindings.join(", "))
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", answers.join("; "))
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Statement {
     Def(DefStatement),
     Query(QueryStatement),
+    Directive(Directive),
+    Test(TestStatement),
+}
+
+///
+/// Represents a goal in the body of a clause.
+///
+#[derive(Debug, PartialEq, Clone)]
+pub enum Goal {
+    Pred(PredicateObj),
+    /// `a, b, ...` inside parentheses.
+    And(Vec<Goal>),
+    /// `a ; b ; ...`
+    Or(Vec<Goal>),
+    /// `(cond -> then ; else)`, where the else branch may be omitted.
+    IfThenElse(Box<Goal>, Box<Goal>, Option<Box<Goal>>),
+}
+
+impl Goal {
+    ///
+    /// Collects the predicate objects which appear in the goal.
+    ///
+    pub fn predicates(&self) -> Vec<&PredicateObj> {
+        let mut preds = Vec::new();
+        self.collect_predicates(&mut preds);
+        preds
+    }
+
+    pub fn predicates_mut(&mut self) -> Vec<&mut PredicateObj> {
+        let mut preds = Vec::new();
+        self.collect_predicates_mut(&mut preds);
+        preds
+    }
+
+    fn collect_predicates_mut<'a>(&'a mut self, preds: &mut Vec<&'a mut PredicateObj>) {
+        match self {
+            Goal::Pred(pred) => preds.push(pred),
+            Goal::And(goals) | Goal::Or(goals) => {
+                for goal in goals {
+                    goal.collect_predicates_mut(preds);
+                }
+            }
+            Goal::IfThenElse(cond, then, otherwise) => {
+                cond.collect_predicates_mut(preds);
+                then.collect_predicates_mut(preds);
+                if let Some(otherwise) = otherwise {
+                    otherwise.collect_predicates_mut(preds);
+                }
+            }
+        }
+    }
+
+    fn collect_predicates<'a>(&'a self, preds: &mut Vec<&'a PredicateObj>) {
+        match self {
+            Goal::Pred(pred) => preds.push(pred),
+            Goal::And(goals) | Goal::Or(goals) => {
+                for goal in goals {
+                    goal.collect_predicates(preds);
+                }
+            }
+            Goal::IfThenElse(cond, then, otherwise) => {
+                cond.collect_predicates(preds);
+                then.collect_predicates(preds);
+                if let Some(otherwise) = otherwise {
+                    otherwise.collect_predicates(preds);
+                }
+            }
+        }
+    }
+}
+
+impl Goal {
+    ///
+    /// Writes a disjunction or an if-then-else without the surrounding parentheses.
+    ///
+    fn fmt_unparenthesized(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Goal::Or(goals) => {
+                let goals = goals.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", goals.join(" ; "))
+            }
+            Goal::IfThenElse(cond, then, otherwise) => {
+                write!(f, "{} -> {}", cond, then)?;
+                if let Some(otherwise) = otherwise {
+                    write!(f, " ; ")?;
+                    otherwise.fmt_unparenthesized(f)?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for Goal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Goal::Pred(pred) => write!(f, "{}", pred),
+            Goal::And(goals) => {
+                let goals = goals.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", goals.join(", "))
+            }
+            Goal::Or(_) | Goal::IfThenElse(_, _, _) => {
+                write!(f, "(")?;
+                self.fmt_unparenthesized(f)?;
+                write!(f, ")")
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct PredicateObj {
-    pub name: String,
+    pub name: Symbol,
     pub arguments: Vec<Expr>,
 }
 
 impl PredicateObj {
-    pub fn new(name: String, arguments: Vec<Expr>) -> Self {
-        PredicateObj { name, arguments }
+    pub fn new(name: impl Into<Symbol>, arguments: Vec<Expr>) -> Self {
+        PredicateObj {
+            name: name.into(),
+            arguments,
+        }
+    }
+
+    pub fn id(&self) -> PredicateId {
+        PredicateId::new(self.name.clone(), self.arguments.len())
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+///
+/// Operators which are written between the two arguments.
+///
+pub const INFIX_OPERATORS: [&str; 4] = ["=", "\\=", "==", "\\=="];
+
+impl fmt::Display for PredicateObj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.arguments.len() == 2 && INFIX_OPERATORS.contains(&self.name.as_str()) {
+            write!(
+                f,
+                "{} {} {}",
+                self.arguments[0], self.name, self.arguments[1]
+            )
+        } else if self.arguments.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            let args = self
+                .arguments
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>();
+            write!(f, "{}({})", self.name, args.join(", "))
+        }
+    }
+}
+
+///
+/// Identifies a predicate by its name and arity, which is written as `name/arity`.
+///
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct PredicateId {
+    pub name: Symbol,
+    pub arity: usize,
+}
+
+/// Separates the module from the name in a qualified name such as `lists:append`.
+pub const MODULE_SEPARATOR: char = ':';
+
+/// The module which unqualified definitions outside any `#module` belong to.
+pub const USER_MODULE: &str = "user";
+
+impl PredicateId {
+    pub fn new(name: impl Into<Symbol>, arity: usize) -> Self {
+        PredicateId {
+            name: name.into(),
+            arity,
+        }
+    }
+
+    ///
+    /// Splits a qualified name into the module and the local name.
+    ///
+    pub fn split_module(&self) -> (Option<&str>, &str) {
+        match self.name.split_once(MODULE_SEPARATOR) {
+            Some((module, name)) => (Some(module), name),
+            None => (None, &self.name),
+        }
+    }
+}
+
+impl FromStr for PredicateId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arity) = s.trim().rsplit_once('/').ok_or(())?;
+        let arity = arity.parse::<usize>().map_err(|_| ())?;
+        Ok(PredicateId::new(name.to_string(), arity))
+    }
+}
+
+impl fmt::Display for PredicateId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.name, self.arity)
+    }
+}
+
+/// The fewest argument lists kept before the unused ones are swept.
+const MIN_SWEEP_LEN: usize = 1024;
+
+///
+/// The argument lists of the terms built so far, each stored once.
+/// A list is dropped by the next sweep once no term refers to it, as the names in `symbol` are.
+///
+struct Terms {
+    arguments: HashSet<Arguments>,
+    /// The number of the lists which triggers the next sweep.
+    sweep_len: usize,
+}
+
+thread_local! {
+    static TERMS: RefCell<Terms> = RefCell::new(Terms {
+        arguments: HashSet::new(),
+        sweep_len: MIN_SWEEP_LEN,
+    });
+}
+
+///
+/// An argument list in the table, which is compared and hashed shallowly:
+/// the compound arguments by their addresses, since they are in the table themselves.
+///
+struct Arguments(Rc<[Expr]>);
+
+impl PartialEq for Arguments {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self.0.iter().zip(other.0.iter()).all(|pair| match pair {
+                (Expr::Atom(lhs), Expr::Atom(rhs)) => {
+                    lhs.name == rhs.name && Rc::ptr_eq(&lhs.arguments, &rhs.arguments)
+                }
+                (Expr::Var(lhs), Expr::Var(rhs)) => lhs == rhs,
+                _ => false,
+            })
+    }
+}
+
+impl Eq for Arguments {}
+
+impl Hash for Arguments {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for arg in self.0.iter() {
+            match arg {
+                Expr::Atom(atom) => {
+                    atom.name.hash(state);
+                    ptr::hash(Rc::as_ptr(&atom.arguments) as *const Expr, state);
+                }
+                Expr::Var(var) => {
+                    var.name.hash(state);
+                    var.id.hash(state);
+                }
+            }
+        }
+    }
+}
+
+///
+/// A compound term, or a constant without arguments.
+/// The arguments are hash-consed: equal argument lists built through `AtomExpr::new` are stored once,
+/// so cloning a term is cheap however large it is, and equal terms are usually told apart by their addresses.
+///
+#[derive(Debug, Clone)]
 pub struct AtomExpr {
-    pub name: String,
-    pub arguments: Vec<Expr>,
+    pub name: Symbol,
+    pub arguments: Rc<[Expr]>,
 }
 
 impl AtomExpr {
-    pub fn new(name: String, arguments: Vec<Expr>) -> Expr {
-        Expr::Atom(AtomExpr { name, arguments })
+    pub fn new(name: impl Into<Symbol>, arguments: Vec<Expr>) -> Expr {
+        Expr::Atom(AtomExpr {
+            name: name.into(),
+            arguments: AtomExpr::intern(arguments),
+        })
+    }
+
+    fn intern(arguments: Vec<Expr>) -> Rc<[Expr]> {
+        TERMS.with_borrow_mut(|terms| {
+            let arguments = Arguments(arguments.into());
+            if let Some(interned) = terms.arguments.get(&arguments) {
+                return interned.0.clone();
+            }
+            // Sweeping only when the table has doubled keeps building terms amortized constant time.
+            if terms.arguments.len() >= terms.sweep_len {
+                terms.arguments.retain(|args| Rc::strong_count(&args.0) > 1);
+                terms.sweep_len = (terms.arguments.len() * 2).max(MIN_SWEEP_LEN);
+            }
+            let interned = arguments.0.clone();
+            terms.arguments.insert(arguments);
+            interned
+        })
+    }
+
+    ///
+    /// Returns the name as written in a program.
+    /// It is quoted as `"New York"` unless it is an identifier or an integer.
+    ///
+    pub fn written_name(&self) -> Cow<'_, str> {
+        let is_ident = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if is_ident || atom_to_int(&self.name).is_some() {
+            Cow::Borrowed(&self.name)
+        } else {
+            let escaped = self.name.replace('\\', "\\\\").replace('"', "\\\"");
+            Cow::Owned(format!("\"{}\"", escaped))
+        }
+    }
+}
+
+impl PartialEq for AtomExpr {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && (Rc::ptr_eq(&self.arguments, &other.arguments) || self.arguments == other.arguments)
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct VarExpr {
-    pub name: String,
+    pub name: Symbol,
     pub id: Option<VarID>,
 }
 
 impl VarExpr {
-    pub fn new(name: String) -> Expr {
-        Expr::Var(VarExpr { name, id: None })
+    pub fn new(name: impl Into<Symbol>) -> Expr {
+        Expr::Var(VarExpr {
+            name: name.into(),
+            id: None,
+        })
     }
 }
 
@@ -86,24 +496,113 @@ pub enum Expr {
     Var(VarExpr),
 }
 
-impl ToString for Expr {
-    fn to_string(&self) -> String {
+/// The functor of a non-empty list `[head | tail]`.
+/// It is not an identifier, so an atom written in a program never becomes a list by accident.
+pub const LIST_CONS: &str = "[|]";
+/// The atom of the empty list `[]`.
+pub const LIST_NIL: &str = "[]";
+
+impl Expr {
+    pub fn nil() -> Expr {
+        AtomExpr::new(LIST_NIL.to_string(), vec![])
+    }
+
+    pub fn cons(head: Expr, tail: Expr) -> Expr {
+        AtomExpr::new(LIST_CONS.to_string(), vec![head, tail])
+    }
+
+    ///
+    /// Builds a list from the items and the tail.
+    ///
+    pub fn list(items: Vec<Expr>, tail: Expr) -> Expr {
+        items
+            .into_iter()
+            .rev()
+            .fold(tail, |tail, head| Expr::cons(head, tail))
+    }
+
+    ///
+    /// Splits a list into its items and the tail, which is `[]` for a proper list.
+    /// Returns `None` if the expression is not a list.
+    ///
+    pub fn as_list(&self) -> Option<(Vec<&Expr>, &Expr)> {
+        let mut items = Vec::new();
+        let mut tail = self;
+        while let Expr::Atom(atom) = tail {
+            if atom.name == LIST_CONS && atom.arguments.len() == 2 {
+                items.push(&atom.arguments[0]);
+                tail = &atom.arguments[1];
+            } else {
+                break;
+            }
+        }
+        if items.is_empty() && !tail.is_nil() {
+            None
+        } else {
+            Some((items, tail))
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Expr::Atom(atom) if atom.name == LIST_NIL && atom.arguments.is_empty())
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((items, tail)) = self.as_list() {
+            let items = items.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+            return if tail.is_nil() {
+                write!(f, "[{}]", items.join(", "))
+            } else {
+                write!(f, "[{} | {}]", items.join(", "), tail)
+            };
+        }
         match self {
             Expr::Atom(atom) => {
-                if atom.arguments.len() == 0 {
-                    atom.name.clone()
+                if atom.arguments.is_empty() {
+                    write!(f, "{}", atom.written_name())
                 } else {
                     let args = atom
                         .arguments
                         .iter()
                         .map(|e| e.to_string())
                         .collect::<Vec<_>>();
-                    format!("{}({})", atom.name, args.join(", "))
+                    write!(f, "{}({})", atom.written_name(), args.join(", "))
                 }
             }
             Expr::Var(var) => {
-                format!("${}", var.name)
+                write!(f, "${}", var.name)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_cons_test() {
+        let build = || {
+            AtomExpr::new(
+                "f",
+                vec![
+                    AtomExpr::new("g", vec![VarExpr::new("x")]),
+                    AtomExpr::new("a", vec![]),
+                ],
+            )
+        };
+        let (Expr::Atom(lhs), Expr::Atom(rhs)) = (build(), build()) else {
+            unreachable!()
+        };
+        // Equal terms built separately share their arguments.
+        assert!(Rc::ptr_eq(&lhs.arguments, &rhs.arguments));
+        assert_eq!(lhs, rhs);
+        let Expr::Atom(other) = AtomExpr::new("f", vec![VarExpr::new("x")]) else {
+            unreachable!()
+        };
+        assert!(!Rc::ptr_eq(&lhs.arguments, &other.arguments));
+        assert_ne!(lhs, other);
+    }
+}
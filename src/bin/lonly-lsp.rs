@@ -0,0 +1,270 @@
+//!
+//! A language server for lonly source files, which talks LSP over the standard input and output.
+//!
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::Range;
+
+use lonly::analysis::{Document, Severity};
+use lonly::parser::Syntax;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as RequestTrait};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind,
+    NumberOrString, OneOf, Position, PublishDiagnosticsParams, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+///
+/// Converts between byte offsets and LSP positions, whose characters are counted in UTF-16.
+///
+fn position_to_offset(source: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match source[line_start..].find('\n') {
+            Some(idx) => line_start += idx + 1,
+            None => return source.len(),
+        }
+    }
+    let mut units = 0;
+    for (idx, c) in source[line_start..].char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return line_start + idx;
+        }
+        units += c.len_utf16();
+    }
+    source.len()
+}
+
+fn offset_to_position(source: &str, offset: usize) -> Position {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+fn to_range(source: &str, range: Range<usize>) -> lsp_types::Range {
+    lsp_types::Range::new(
+        offset_to_position(source, range.start),
+        offset_to_position(source, range.end),
+    )
+}
+
+struct Server {
+    connection: Connection,
+    syntax: Syntax,
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn update(&mut self, uri: Url, text: &str) -> Result<(), Box<dyn Error>> {
+        let doc = Document::new(text, self.syntax);
+        let diagnostics = doc
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| Diagnostic {
+                range: to_range(doc.source(), diagnostic.range.clone()),
+                severity: Some(match diagnostic.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                }),
+                code: Some(NumberOrString::String(diagnostic.code.to_string())),
+                source: Some("lonly".to_string()),
+                message: diagnostic.message.to_string(),
+                ..Diagnostic::default()
+            })
+            .collect();
+        self.documents.insert(uri.clone(), doc);
+        self.notify::<PublishDiagnostics>(PublishDiagnosticsParams::new(uri, diagnostics, None))
+    }
+
+    fn notify<N: NotificationTrait>(&self, params: N::Params) -> Result<(), Box<dyn Error>> {
+        let notification = Notification::new(N::METHOD.to_string(), params);
+        self.connection
+            .sender
+            .send(Message::Notification(notification))?;
+        Ok(())
+    }
+
+    fn respond<R: RequestTrait>(
+        &self,
+        id: RequestId,
+        result: R::Result,
+    ) -> Result<(), Box<dyn Error>> {
+        let response = Response::new_ok(id, result);
+        self.connection.sender.send(Message::Response(response))?;
+        Ok(())
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> Result<(), Box<dyn Error>> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = extract_notification::<DidOpenTextDocument>(notification)?;
+                self.update(params.text_document.uri, &params.text_document.text)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = extract_notification::<DidChangeTextDocument>(notification)?;
+                // The whole text is sent on every change.
+                match params.content_changes.last() {
+                    Some(change) => self.update(params.text_document.uri, &change.text),
+                    None => Ok(()),
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = extract_notification::<DidCloseTextDocument>(notification)?;
+                self.documents.remove(&params.text_document.uri);
+                let uri = params.text_document.uri;
+                self.notify::<PublishDiagnostics>(PublishDiagnosticsParams::new(uri, vec![], None))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_request(&mut self, request: Request) -> Result<(), Box<dyn Error>> {
+        match request.method.as_str() {
+            GotoDefinition::METHOD => {
+                let (id, params) =
+                    request.extract::<GotoDefinitionParams>(GotoDefinition::METHOD)?;
+                let params = params.text_document_position_params;
+                let uri = params.text_document.uri;
+                let locations = self.documents.get(&uri).map(|doc| {
+                    let offset = position_to_offset(doc.source(), params.position);
+                    let locations = doc
+                        .definition(offset)
+                        .into_iter()
+                        .map(|range| Location::new(uri.clone(), to_range(doc.source(), range)))
+                        .collect();
+                    GotoDefinitionResponse::Array(locations)
+                });
+                self.respond::<GotoDefinition>(id, locations)
+            }
+            HoverRequest::METHOD => {
+                let (id, params) = request.extract::<HoverParams>(HoverRequest::METHOD)?;
+                let params = params.text_document_position_params;
+                let hover = self
+                    .documents
+                    .get(&params.text_document.uri)
+                    .and_then(|doc| {
+                        let offset = position_to_offset(doc.source(), params.position);
+                        let value = doc.hover(offset)?;
+                        Some(Hover {
+                            contents: HoverContents::Markup(MarkupContent {
+                                kind: MarkupKind::Markdown,
+                                value,
+                            }),
+                            range: None,
+                        })
+                    });
+                self.respond::<HoverRequest>(id, hover)
+            }
+            Completion::METHOD => {
+                let (id, params) = request.extract::<CompletionParams>(Completion::METHOD)?;
+                let params = params.text_document_position;
+                let items = self.documents.get(&params.text_document.uri).map(|doc| {
+                    let offset = position_to_offset(doc.source(), params.position);
+                    let items = doc
+                        .completions(offset)
+                        .into_iter()
+                        .map(|completion| CompletionItem {
+                            kind: Some(if completion.detail == "variable" {
+                                CompletionItemKind::VARIABLE
+                            } else {
+                                CompletionItemKind::FUNCTION
+                            }),
+                            label: completion.label,
+                            detail: Some(completion.detail),
+                            ..CompletionItem::default()
+                        })
+                        .collect();
+                    CompletionResponse::Array(items)
+                });
+                self.respond::<Completion>(id, items)
+            }
+            _ => {
+                // Unsupported requests are answered so that the client does not wait.
+                let response = Response::new_err(
+                    request.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("\"{}\" is not supported.", request.method),
+                );
+                self.connection.sender.send(Message::Response(response))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn extract_notification<N: NotificationTrait>(
+    notification: Notification,
+) -> Result<N::Params, Box<dyn Error>> {
+    Ok(notification.extract(N::METHOD)?)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut syntax = Syntax::Terminated;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--compat" => syntax = Syntax::Compat,
+            // Editors often pass `--stdio`, which is the only transport.
+            "--stdio" => {}
+            _ => {
+                eprintln!("Usage: lonly-lsp [--compat]");
+                return Ok(());
+            }
+        }
+    }
+
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["$".to_string()]),
+            ..CompletionOptions::default()
+        }),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut server = Server {
+        connection,
+        syntax,
+        documents: HashMap::new(),
+    };
+    while let Ok(message) = server.connection.receiver.recv() {
+        match message {
+            Message::Request(request) => {
+                if server.connection.handle_shutdown(&request)? {
+                    break;
+                }
+                // A malformed request is answered with an error so that the session goes on.
+                let id = request.id.clone();
+                if let Err(err) = server.handle_request(request) {
+                    eprintln!("ERR: {}", err);
+                    let response =
+                        Response::new_err(id, ErrorCode::InvalidParams as i32, err.to_string());
+                    server.connection.sender.send(Message::Response(response))?;
+                }
+            }
+            Message::Notification(notification) => {
+                if let Err(err) = server.handle_notification(notification) {
+                    eprintln!("ERR: {}", err);
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    drop(server);
+    io_threads.join()?;
+    Ok(())
+}
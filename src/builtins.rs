@@ -0,0 +1,269 @@
+use std::cmp::Ordering;
+
+use crate::{
+    ast::{AtomExpr, Expr, PredicateId, PredicateObj, VarExpr},
+    error::ErrorKind,
+};
+
+///
+/// Predicates which are implemented by the interpreter itself.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// `asserta(Head, Premises...)` adds a clause before the existing ones.
+    AssertA,
+    /// `assertz(Head, Premises...)` adds a clause after the existing ones.
+    AssertZ,
+    /// `retract(Head, Premises...)` removes the first clause which unifies with the arguments.
+    Retract,
+    /// `$x = $y` unifies the arguments.
+    Unify,
+    /// `$x \= $y` succeeds if the arguments do not unify.
+    NotUnifiable,
+    /// `$x == $y` succeeds if the arguments are identical without binding variables.
+    Identical,
+    /// `$x \== $y` succeeds if the arguments are not identical.
+    NotIdentical,
+    /// `dif($x, $y)` constrains the arguments never to become identical.
+    /// The check is delayed until the arguments are sufficiently instantiated.
+    Dif,
+    /// `findall(Template, Goal, List)` collects the instances of the template for all the solutions.
+    FindAll,
+    /// `bagof(Template, Goal, List)` is similar to `findall/3`, but fails if there is no solution
+    /// and groups the solutions by the bindings of the free variables in the goal.
+    BagOf,
+    /// `setof(Template, Goal, List)` is `bagof/3` with each list sorted and deduplicated.
+    SetOf,
+    /// `aggregate_all(Spec, Goal, Result)` where `Spec` is one of
+    /// `count`, `sum(Expr)`, `max(Expr)`, `min(Expr)`, `bag(Expr)` and `set(Expr)`.
+    AggregateAll,
+    /// `call(Goal, Args...)` runs the goal term with the extra arguments appended.
+    Call,
+    /// `throw(Ball)` raises an exception, which is caught by the innermost matching `catch/3`.
+    Throw,
+    /// `catch(Goal, Catcher, Recovery)` runs the goal, and runs the recovery instead
+    /// if the goal throws an exception which unifies with the catcher.
+    Catch,
+}
+
+impl Builtin {
+    pub fn lookup(id: &PredicateId) -> Option<Builtin> {
+        match (id.name.as_str(), id.arity) {
+            ("asserta", 1..) => Some(Builtin::AssertA),
+            ("assertz", 1..) => Some(Builtin::AssertZ),
+            ("retract", 1..) => Some(Builtin::Retract),
+            ("=", 2) => Some(Builtin::Unify),
+            ("\\=", 2) => Some(Builtin::NotUnifiable),
+            ("==", 2) => Some(Builtin::Identical),
+            ("\\==", 2) => Some(Builtin::NotIdentical),
+            ("dif", 2) => Some(Builtin::Dif),
+            ("findall", 3) => Some(Builtin::FindAll),
+            ("bagof", 3) => Some(Builtin::BagOf),
+            ("setof", 3) => Some(Builtin::SetOf),
+            ("aggregate_all", 3) => Some(Builtin::AggregateAll),
+            ("call", 1..=8) => Some(Builtin::Call),
+            ("throw", 1) => Some(Builtin::Throw),
+            ("catch", 3) => Some(Builtin::Catch),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Lists the arguments of the built-in predicate which are called as goals,
+    /// each with the number of the arguments appended when it is called.
+    ///
+    pub fn meta_arguments(&self, arity: usize) -> Vec<(usize, usize)> {
+        match self {
+            Builtin::Call => vec![(0, arity - 1)],
+            Builtin::FindAll | Builtin::BagOf | Builtin::SetOf | Builtin::AggregateAll => {
+                vec![(1, 0)]
+            }
+            Builtin::Catch => vec![(0, 0), (2, 0)],
+            _ => vec![],
+        }
+    }
+}
+
+///
+/// Converts a term into a predicate object so that it can be used as a goal or a clause.
+///
+pub fn expr_to_predicate(expr: &Expr, caller: &PredicateId) -> Result<PredicateObj, ErrorKind> {
+    match expr {
+        Expr::Atom(atom) => Ok(PredicateObj::new(
+            atom.name.clone(),
+            atom.arguments.to_vec(),
+        )),
+        Expr::Var(_) => Err(ErrorKind::NotInstantiated(caller.clone())),
+    }
+}
+
+///
+/// Drops the variable IDs so that the term can be stored as a part of a clause.
+/// Variables are renamed after their IDs since different variables may share a name.
+///
+pub fn clear_var_ids(expr: &Expr) -> Expr {
+    match expr {
+        Expr::Atom(atom) => AtomExpr::new(
+            atom.name.clone(),
+            atom.arguments.iter().map(clear_var_ids).collect(),
+        ),
+        Expr::Var(var) => match var.id {
+            Some(id) => VarExpr::new(format!("_{}", id)),
+            None => VarExpr::new(var.name.clone()),
+        },
+    }
+}
+
+fn atom(name: &str, arguments: Vec<Expr>) -> Expr {
+    AtomExpr::new(name, arguments)
+}
+
+///
+/// Converts an error into the term which `catch/3` receives, such as
+/// `error(type_error(integer, foo), context(aggregate_all/3, Message))`, where `Message` is the text of the error.
+/// Returns `None` for the errors which cannot be caught.
+///
+pub fn error_term(err: &ErrorKind) -> Option<Expr> {
+    let indicator = |id: &PredicateId| {
+        let arity = atom(&id.arity.to_string(), vec![]);
+        atom("/", vec![atom(&id.name, vec![]), arity])
+    };
+    let (error, context) = match err {
+        ErrorKind::Thrown(ball) => return Some(ball.clone()),
+        ErrorKind::NotInstantiated(id) => (atom("instantiation_error", vec![]), indicator(id)),
+        ErrorKind::TypeMismatch(id, expected, culprit) => (
+            atom("type_error", vec![atom(expected, vec![]), culprit.clone()]),
+            indicator(id),
+        ),
+        ErrorKind::UnknownPredicate(id) => (
+            atom(
+                "existence_error",
+                vec![atom("procedure", vec![]), indicator(id)],
+            ),
+            indicator(id),
+        ),
+        ErrorKind::BuiltinRedefinition(id) => (
+            atom(
+                "permission_error",
+                vec![
+                    atom("modify", vec![]),
+                    atom("static_procedure", vec![]),
+                    indicator(id),
+                ],
+            ),
+            indicator(id),
+        ),
+        ErrorKind::PrivatePredicate(id) => (
+            atom(
+                "permission_error",
+                vec![
+                    atom("access", vec![]),
+                    atom("private_procedure", vec![]),
+                    indicator(id),
+                ],
+            ),
+            indicator(id),
+        ),
+        _ => return None,
+    };
+    let context = atom("context", vec![context, atom(&err.to_string(), vec![])]);
+    Some(atom("error", vec![error, context]))
+}
+
+///
+/// Reads the name of an atom as an integer if it is the decimal form of one,
+/// such as `42` or `-7`, but not `007` or `+5`, which are other atoms.
+///
+pub fn atom_to_int(name: &str) -> Option<i64> {
+    name.parse::<i64>()
+        .ok()
+        .filter(|value| value.to_string() == name)
+}
+
+///
+/// Reads an integer, which is written as an atom such as `42`.
+///
+pub fn expr_to_int(expr: &Expr, caller: &PredicateId) -> Result<i64, ErrorKind> {
+    match expr {
+        Expr::Atom(atom) if atom.arguments.is_empty() => atom_to_int(&atom.name).ok_or_else(|| {
+            ErrorKind::TypeMismatch(caller.clone(), "integer".to_string(), expr.clone())
+        }),
+        Expr::Atom(_) => Err(ErrorKind::TypeMismatch(
+            caller.clone(),
+            "integer".to_string(),
+            expr.clone(),
+        )),
+        Expr::Var(_) => Err(ErrorKind::NotInstantiated(caller.clone())),
+    }
+}
+
+pub fn int_to_expr(value: i64) -> Expr {
+    AtomExpr::new(value.to_string(), vec![])
+}
+
+///
+/// Collects the variables in the expression without duplicates.
+///
+pub fn collect_vars(expr: &Expr, vars: &mut Vec<Expr>) {
+    match expr {
+        Expr::Atom(atom) => {
+            for arg in atom.arguments.iter() {
+                collect_vars(arg, vars);
+            }
+        }
+        Expr::Var(var) => {
+            let is_new = vars
+                .iter()
+                .all(|other| !matches!(other, Expr::Var(other) if other.id == var.id));
+            if is_new {
+                vars.push(expr.clone());
+            }
+        }
+    }
+}
+
+///
+/// Compares two terms in the standard order:
+/// variables, integers, atoms and then compound terms.
+///
+pub fn compare_terms(lhs: &Expr, rhs: &Expr) -> Ordering {
+    fn rank(expr: &Expr) -> u8 {
+        match expr {
+            Expr::Var(_) => 0,
+            Expr::Atom(atom) if atom.arguments.is_empty() => {
+                if atom_to_int(&atom.name).is_some() {
+                    1
+                } else {
+                    2
+                }
+            }
+            Expr::Atom(_) => 3,
+        }
+    }
+
+    match (lhs, rhs) {
+        (Expr::Var(lhs), Expr::Var(rhs)) => lhs.id.cmp(&rhs.id),
+        (Expr::Atom(lhs_atom), Expr::Atom(rhs_atom)) if rank(lhs) == rank(rhs) => {
+            if rank(lhs) == 1 {
+                let lhs = atom_to_int(&lhs_atom.name).unwrap();
+                let rhs = atom_to_int(&rhs_atom.name).unwrap();
+                return lhs.cmp(&rhs);
+            }
+            lhs_atom
+                .arguments
+                .len()
+                .cmp(&rhs_atom.arguments.len())
+                .then_with(|| lhs_atom.name.cmp(&rhs_atom.name))
+                .then_with(|| {
+                    lhs_atom
+                        .arguments
+                        .iter()
+                        .zip(rhs_atom.arguments.iter())
+                        .map(|(lhs, rhs)| compare_terms(lhs, rhs))
+                        .find(|ord| ord.is_ne())
+                        .unwrap_or(Ordering::Equal)
+                })
+        }
+        _ => rank(lhs).cmp(&rank(rhs)),
+    }
+}
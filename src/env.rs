@@ -1,25 +1,63 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    io,
+    str::FromStr,
+};
 
 use crate::{
-    ast::{DefStatement, Expr, PredicateObj, VarID},
-    error::ErrorKind,
+    ast::{
+        AtomExpr, DefStatement, Directive, Expr, Goal, PredicateId, PredicateObj, Statement,
+        VarExpr, VarID, MODULE_SEPARATOR, USER_MODULE,
+    },
+    builtins::Builtin,
+    error::{ErrorKind, WarningKind},
+    format::{format_statement, DEFAULT_WIDTH},
+    image::{ImageReader, ImageWriter, IMAGE_VERSION},
+    parser::Syntax,
+    prelude,
+    symbol::Symbol,
+    unifier::unify_exprs,
 };
 
+///
+/// A clause consisting of the conclusion and the premises.
+///
+pub type Clause = (PredicateObj, Vec<Goal>);
+
 #[derive(Debug)]
 struct Predicate {
-    length: usize,
-    pub rules: Vec<(PredicateObj, Vec<PredicateObj>)>,
+    pub rules: Vec<Clause>,
+    /// Whether the clauses have been modified at runtime.
+    /// A dynamic predicate fails silently even if it has no clauses.
+    pub dynamic: bool,
+    /// Whether the clauses come from the prelude.
+    /// A library predicate is replaced as a whole when the user defines it.
+    pub library: bool,
 }
 
 impl Predicate {
-    pub fn new(length: usize) -> Self {
+    pub fn new() -> Self {
         Self {
-            length,
             rules: Vec::new(),
+            dynamic: false,
+            library: false,
         }
     }
 }
 
+///
+/// Declarations of a module.
+///
+#[derive(Debug, Default)]
+struct Module {
+    /// The predicates visible outside the module.
+    exports: Vec<PredicateId>,
+    /// The modules whose exported predicates can be called without qualification.
+    imports: Vec<String>,
+}
+
+#[derive(Default)]
 pub struct VarAllocator {
     num_vars: u32,
 }
@@ -37,29 +75,59 @@ impl VarAllocator {
 
     pub fn assign_new_ids(
         &mut self,
-        exprs: &mut Vec<Expr>,
-        assigned: &mut HashMap<String, u32>,
+        exprs: &mut [Expr],
+        assigned: &mut HashMap<Symbol, VarID>,
     ) -> Result<(), ErrorKind> {
         for expr in exprs {
-            match expr {
-                Expr::Var(var) => {
-                    if var.id == None {
-                        if let Some(id) = assigned.get(&var.name) {
-                            var.id = Some(*id);
-                        } else {
-                            let id = self.gen_new_id();
-                            assigned.insert(var.name.to_string(), id);
-                            var.id = Some(id);
-                        }
-                    } else {
-                        Err(ErrorKind::VariableIDAlreadyAssigned(var.name.to_string()))?
-                    }
+            if let Some(renamed) = self.renamed(expr, assigned)? {
+                *expr = renamed;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Returns the expression with IDs assigned to its variables, or `None` if it has no variables.
+    /// The subterms without variables are shared with the original instead of copied.
+    ///
+    fn renamed(
+        &mut self,
+        expr: &Expr,
+        assigned: &mut HashMap<Symbol, VarID>,
+    ) -> Result<Option<Expr>, ErrorKind> {
+        match expr {
+            Expr::Var(var) => {
+                if var.id.is_some() {
+                    Err(ErrorKind::VariableIDAlreadyAssigned(var.name.to_string()))?
                 }
-                Expr::Atom(atom) => {
-                    self.assign_new_ids(&mut atom.arguments, assigned)?;
+                let id = *assigned
+                    .entry(var.name.clone())
+                    .or_insert_with(|| self.gen_new_id());
+                Ok(Some(Expr::Var(VarExpr {
+                    name: var.name.clone(),
+                    id: Some(id),
+                })))
+            }
+            Expr::Atom(atom) => {
+                let mut arguments = None;
+                for (idx, arg) in atom.arguments.iter().enumerate() {
+                    if let Some(renamed) = self.renamed(arg, assigned)? {
+                        arguments.get_or_insert_with(|| atom.arguments.to_vec())[idx] = renamed;
+                    }
                 }
+                Ok(arguments.map(|arguments| AtomExpr::new(atom.name.clone(), arguments)))
             }
         }
+    }
+
+    pub fn assign_goal_ids(
+        &mut self,
+        goal: &mut Goal,
+        assigned: &mut HashMap<Symbol, VarID>,
+    ) -> Result<(), ErrorKind> {
+        for pred in goal.predicates_mut() {
+            self.assign_new_ids(&mut pred.arguments, assigned)?;
+        }
         Ok(())
     }
 }
@@ -67,7 +135,7 @@ impl VarAllocator {
 ///
 /// Represents the substitution of variables.
 ///
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct VarSubstitution {
     subst: HashMap<VarID, Expr>,
 }
@@ -87,19 +155,39 @@ impl VarSubstitution {
         self.subst.get(&id)
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.subst.is_empty()
+    }
+
     pub fn substitute(&self, expr: &mut Expr) {
+        if let Some(substituted) = self.substituted(expr) {
+            *expr = substituted;
+        }
+    }
+
+    ///
+    /// Returns the expression with the substitution applied, or `None` if it has no variable to replace.
+    /// The subterms without such variables are shared with the original instead of copied.
+    ///
+    fn substituted(&self, expr: &Expr) -> Option<Expr> {
         match expr {
             Expr::Atom(atom) => {
-                for arg in &mut atom.arguments {
-                    self.substitute(arg);
-                }
-            }
-            Expr::Var(var) => {
-                if let Some(id) = var.id {
-                    if let Some(repl) = self.get(id) {
-                        *expr = repl.clone();
+                let mut arguments = None;
+                for (idx, arg) in atom.arguments.iter().enumerate() {
+                    if let Some(substituted) = self.substituted(arg) {
+                        arguments.get_or_insert_with(|| atom.arguments.to_vec())[idx] = substituted;
                     }
                 }
+                arguments.map(|arguments| AtomExpr::new(atom.name.clone(), arguments))
+            }
+            Expr::Var(var) => self.get(var.id?).cloned(),
+        }
+    }
+
+    pub fn substitute_goal(&self, goal: &mut Goal) {
+        for pred in goal.predicates_mut() {
+            for arg in &mut pred.arguments {
+                self.substitute(arg);
             }
         }
     }
@@ -119,71 +207,878 @@ impl VarSubstitution {
     }
 }
 
+///
+/// Pairs up the arguments of two goals of the same shape for unification.
+/// Returns false if the shapes differ.
+///
+fn pair_goals(lhs: &Goal, rhs: &Goal, exprs: &mut VecDeque<(Expr, Expr)>) -> bool {
+    match (lhs, rhs) {
+        (Goal::Pred(lhs), Goal::Pred(rhs)) => {
+            if lhs.id() != rhs.id() {
+                return false;
+            }
+            for (lhs, rhs) in lhs.arguments.iter().zip(&rhs.arguments) {
+                exprs.push_back((lhs.clone(), rhs.clone()));
+            }
+            true
+        }
+        (Goal::And(lhs), Goal::And(rhs)) | (Goal::Or(lhs), Goal::Or(rhs)) => {
+            lhs.len() == rhs.len()
+                && lhs
+                    .iter()
+                    .zip(rhs)
+                    .all(|(lhs, rhs)| pair_goals(lhs, rhs, exprs))
+        }
+        (
+            Goal::IfThenElse(lhs_cond, lhs_then, lhs_else),
+            Goal::IfThenElse(rhs_cond, rhs_then, rhs_else),
+        ) => {
+            pair_goals(lhs_cond, rhs_cond, exprs)
+                && pair_goals(lhs_then, rhs_then, exprs)
+                && match (lhs_else, rhs_else) {
+                    (Some(lhs), Some(rhs)) => pair_goals(lhs, rhs, exprs),
+                    (None, None) => true,
+                    _ => false,
+                }
+        }
+        _ => false,
+    }
+}
+
+///
+/// Decides what happens when a goal calls a predicate which has no clauses.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownMode {
+    Fail,
+    Warning,
+    Error,
+}
+
+impl FromStr for UnknownMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(UnknownMode::Fail),
+            "warning" => Ok(UnknownMode::Warning),
+            "error" => Ok(UnknownMode::Error),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Environment {
-    predicates: HashMap<String, Predicate>,
+    predicates: HashMap<PredicateId, Predicate>,
+    modules: HashMap<String, Module>,
+    /// The calls from the clauses of each predicate to other modules which were not loaded when the clauses were written.
+    /// Whether they are exported is checked when the predicate is called.
+    deferred: HashMap<PredicateId, Vec<(String, PredicateId)>>,
+    unknown: UnknownMode,
+    strict_arity: bool,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Environment {
     pub fn new() -> Self {
         Self {
             predicates: HashMap::new(),
+            modules: HashMap::new(),
+            deferred: HashMap::new(),
+            unknown: UnknownMode::Warning,
+            strict_arity: false,
         }
     }
 
-    pub fn get_rules(&self, name: &str) -> Option<&Vec<(PredicateObj, Vec<PredicateObj>)>> {
-        self.predicates.get(name).map(|pred| &pred.rules)
+    pub fn unknown(&self) -> UnknownMode {
+        self.unknown
     }
 
-    pub fn validate(&mut self, pred_obj: &PredicateObj) -> Result<(), ErrorKind> {
-        let arg_len = pred_obj.arguments.len();
-        match self.predicates.get_mut(&pred_obj.name) {
-            Some(pred) => {
-                if arg_len != pred.length {
-                    Err(ErrorKind::ArityMismatch(
-                        pred_obj.name.to_string(),
-                        arg_len,
-                        pred.length,
-                    ))
-                } else {
-                    Ok(())
+    pub fn set_unknown(&mut self, mode: UnknownMode) {
+        self.unknown = mode;
+    }
+
+    ///
+    /// Enables the lint which rejects predicates sharing a name with another arity.
+    ///
+    pub fn set_strict_arity(&mut self, strict_arity: bool) {
+        self.strict_arity = strict_arity;
+    }
+
+    pub fn get_rules(&self, id: &PredicateId) -> Option<&Vec<Clause>> {
+        self.predicates.get(id).map(|pred| &pred.rules)
+    }
+
+    ///
+    /// Lists the user-defined predicates in the order of their names and arities.
+    ///
+    pub fn predicate_ids(&self) -> Vec<&PredicateId> {
+        let mut ids = self
+            .predicates
+            .iter()
+            .filter(|(_, pred)| pred.dynamic || !pred.rules.is_empty())
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    ///
+    /// Removes all the clauses of the predicate.
+    /// Returns whether the predicate has been defined.
+    ///
+    pub fn clear(&mut self, id: &PredicateId) -> bool {
+        self.deferred.remove(id);
+        self.predicates.remove(id).is_some()
+    }
+
+    ///
+    /// Removes all the predicates while keeping the settings.
+    ///
+    pub fn reset(&mut self) {
+        self.predicates.clear();
+        self.modules.clear();
+        self.deferred.clear();
+    }
+
+    pub fn is_defined(&self, id: &PredicateId) -> bool {
+        Builtin::lookup(id).is_some() || self.has_clauses(id)
+    }
+
+    fn has_clauses(&self, id: &PredicateId) -> bool {
+        self.predicates
+            .get(id)
+            .is_some_and(|pred| pred.dynamic || !pred.rules.is_empty())
+    }
+
+    ///
+    /// Applies a directive. `module` is the module which the following definitions belong to.
+    ///
+    pub fn apply_directive(
+        &mut self,
+        module: &mut String,
+        directive: Directive,
+    ) -> Result<(), ErrorKind> {
+        match directive {
+            Directive::Module(name) => {
+                self.modules.entry(name.to_string()).or_default();
+                *module = name;
+            }
+            Directive::Export(ids) => {
+                if module == USER_MODULE {
+                    Err(ErrorKind::ExportOutsideModule)?
+                }
+                let exports = &mut self.modules.entry(module.to_string()).or_default().exports;
+                for id in ids {
+                    if !exports.contains(&id) {
+                        exports.push(id);
+                    }
                 }
             }
-            None => {
-                self.predicates
-                    .insert(pred_obj.name.to_string(), Predicate::new(arg_len));
-                Ok(())
+            Directive::Import(name) => {
+                let imports = &mut self.modules.entry(module.to_string()).or_default().imports;
+                if !imports.contains(&name) {
+                    imports.push(name);
+                }
             }
+            Directive::Dynamic(ids) => {
+                for id in ids {
+                    if Builtin::lookup(&id).is_some() {
+                        Err(ErrorKind::BuiltinRedefinition(id.clone()))?
+                    }
+                    let id = match id.split_module() {
+                        (None, name) if module != USER_MODULE => PredicateId::new(
+                            format!("{}{}{}", module, MODULE_SEPARATOR, name),
+                            id.arity,
+                        ),
+                        _ => id,
+                    };
+                    if self.is_library(&id) {
+                        self.predicates.insert(id.clone(), Predicate::new());
+                    }
+                    self.predicates
+                        .entry(id)
+                        .or_insert_with(Predicate::new)
+                        .dynamic = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Qualifies a predicate object written in the module,
+    /// and checks that a predicate of another module is called only if it is exported.
+    /// The goals and the closures passed to meta-predicates such as `call/N` and `maplist/3` are qualified as well,
+    /// so that they are called in the module where they are written.
+    ///
+    pub fn qualify(&self, module: &str, pred_obj: &mut PredicateObj) -> Result<(), ErrorKind> {
+        self.qualify_name(module, pred_obj)?;
+        for (idx, extra) in self.meta_arguments(&pred_obj.id()) {
+            let Expr::Atom(closure) = &pred_obj.arguments[idx] else {
+                continue;
+            };
+            let mut goal = PredicateObj::new(closure.name.clone(), closure.arguments.to_vec());
+            if extra == 0 {
+                self.qualify(module, &mut goal)?;
+            } else if module != USER_MODULE && goal.id().split_module().0.is_none() {
+                // The arity is not known until the closure is called.
+                goal.name = format!("{}{}{}", module, MODULE_SEPARATOR, goal.name).into();
+            }
+            pred_obj.arguments[idx] = AtomExpr::new(goal.name, goal.arguments);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Qualifies the name of a predicate object written in the module, leaving the arguments as they are,
+    /// and checks that a predicate of another module is called only if it is exported.
+    ///
+    fn qualify_name(&self, module: &str, pred_obj: &mut PredicateObj) -> Result<(), ErrorKind> {
+        let id = pred_obj.id();
+        match id.split_module() {
+            (Some(_), _) => {
+                if self.is_private(module, &id) == Some(true) {
+                    Err(ErrorKind::PrivatePredicate(id.clone()))?
+                }
+            }
+            (None, _) => {
+                if module != USER_MODULE && Builtin::lookup(&id).is_none() {
+                    pred_obj.name =
+                        format!("{}{}{}", module, MODULE_SEPARATOR, pred_obj.name).into();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Checks whether a qualified predicate is hidden from the module.
+    /// Returns `None` if the module of the predicate is not loaded yet.
+    ///
+    fn is_private(&self, module: &str, id: &PredicateId) -> Option<bool> {
+        let (Some(other), name) = id.split_module() else {
+            return Some(false);
+        };
+        if other == module {
+            return Some(false);
+        }
+        let local_id = PredicateId::new(name.to_string(), id.arity);
+        let other = self.modules.get(other)?;
+        Some(!other.exports.contains(&local_id))
+    }
+
+    ///
+    /// Adds a clause written in the module.
+    /// A call to a module not loaded yet is checked when the predicate of the clause is called.
+    ///
+    pub fn define(&mut self, module: &str, mut stmt: DefStatement) -> Result<(), ErrorKind> {
+        // The arguments of the head are not goals, even if it is named like a meta-predicate.
+        self.qualify_name(module, &mut stmt.conclusion)?;
+        let mut deferred = Vec::new();
+        for premise in stmt.premises.iter_mut().flat_map(Goal::predicates_mut) {
+            self.qualify(module, premise)?;
+            if self.is_private(module, &premise.id()).is_none() {
+                deferred.push((module.to_string(), premise.id()));
+            }
+        }
+        let id = stmt.conclusion.id();
+        self.update(stmt)?;
+        if !deferred.is_empty() {
+            self.deferred.entry(id).or_default().extend(deferred);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Checks that the clauses of the predicate call only the exported predicates of other modules,
+    /// which is left until the predicate is called if a module was not loaded when the clauses were written.
+    ///
+    pub fn check_calls(&self, id: &PredicateId) -> Result<(), ErrorKind> {
+        for (module, callee) in self.deferred.get(id).map_or(&[][..], Vec::as_slice) {
+            if self.is_private(module, callee) == Some(true) {
+                Err(ErrorKind::PrivatePredicate(callee.clone()))?
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Lists the arguments which are called as goals by the predicate a goal calls,
+    /// each with the number of the arguments appended when it is called.
+    /// The positions are declared by the built-in predicates and the meta-predicates of the prelude,
+    /// so a predicate the user defines with the same name takes its arguments as they are.
+    ///
+    fn meta_arguments(&self, id: &PredicateId) -> Vec<(usize, usize)> {
+        let id = self.resolve(id);
+        if let Some(builtin) = Builtin::lookup(&id) {
+            builtin.meta_arguments(id.arity)
+        } else if self.is_library(&id) {
+            prelude::meta_arguments(&id)
+        } else {
+            vec![]
         }
     }
 
+    ///
+    /// Finds the predicate which a goal calls.
+    /// A predicate of the module itself is preferred to the imported ones,
+    /// which are preferred to the ones outside any module.
+    ///
+    pub fn resolve(&self, id: &PredicateId) -> PredicateId {
+        if self.has_clauses(id) {
+            return id.clone();
+        }
+        let (module, name) = id.split_module();
+        let local_id = PredicateId::new(name.to_string(), id.arity);
+
+        let imports = self
+            .modules
+            .get(module.unwrap_or(USER_MODULE))
+            .map_or(&[][..], |module| &module.imports);
+        for import in imports {
+            let is_exported = self
+                .modules
+                .get(import)
+                .is_some_and(|import| import.exports.contains(&local_id));
+            if is_exported {
+                let name = format!("{}{}{}", import, MODULE_SEPARATOR, name);
+                return PredicateId::new(name, id.arity);
+            }
+        }
+
+        if module.is_some() && self.is_defined(&local_id) {
+            local_id
+        } else {
+            id.clone()
+        }
+    }
+
+    ///
+    /// Lists the premises which refer to predicates with no clauses.
+    ///
+    pub fn check_undefined(&self) -> Vec<WarningKind> {
+        let mut ids = self.predicates.keys().collect::<Vec<_>>();
+        ids.sort();
+
+        let mut warnings = Vec::new();
+        for id in ids {
+            for (_, premises) in &self.predicates[id].rules {
+                for premise in premises.iter().flat_map(Goal::predicates) {
+                    let premise_id = self.resolve(&premise.id());
+                    if !self.is_defined(&premise_id) {
+                        warnings.push(WarningKind::UndefinedPremise(id.clone(), premise_id));
+                    }
+                }
+            }
+        }
+        warnings
+    }
+
+    pub fn validate(&mut self, pred_obj: &PredicateObj) -> Result<(), ErrorKind> {
+        let id = pred_obj.id();
+        if self.strict_arity {
+            let other = self
+                .predicates
+                .keys()
+                .find(|other| other.name == id.name && other.arity != id.arity);
+            if let Some(other) = other {
+                Err(ErrorKind::ArityMismatch(
+                    id.name.to_string(),
+                    other.arity,
+                    id.arity,
+                ))?
+            }
+        }
+        self.predicates.entry(id).or_insert_with(Predicate::new);
+        Ok(())
+    }
+
+    pub fn is_library(&self, id: &PredicateId) -> bool {
+        self.predicates.get(id).is_some_and(|pred| pred.library)
+    }
+
     pub fn update(&mut self, stmt: DefStatement) -> Result<(), ErrorKind> {
+        let id = stmt.conclusion.id();
+        if self.is_library(&id) {
+            self.predicates.insert(id, Predicate::new());
+        }
+        self.add_clause(stmt, false)?;
+        Ok(())
+    }
+
+    ///
+    /// Adds a clause of the prelude.
+    ///
+    pub fn load_library(&mut self, stmt: DefStatement) -> Result<(), ErrorKind> {
+        self.add_clause(stmt, false)?.library = true;
+        Ok(())
+    }
+
+    ///
+    /// Adds a clause at runtime before the existing clauses of the predicate.
+    ///
+    pub fn asserta(&mut self, stmt: DefStatement) -> Result<(), ErrorKind> {
+        self.add_clause(stmt, true)?.dynamic = true;
+        Ok(())
+    }
+
+    ///
+    /// Adds a clause at runtime after the existing clauses of the predicate.
+    ///
+    pub fn assertz(&mut self, stmt: DefStatement) -> Result<(), ErrorKind> {
+        self.add_clause(stmt, false)?.dynamic = true;
+        Ok(())
+    }
+
+    fn add_clause(&mut self, stmt: DefStatement, front: bool) -> Result<&mut Predicate, ErrorKind> {
+        let id = stmt.conclusion.id();
+        if Builtin::lookup(&id).is_some() {
+            Err(ErrorKind::BuiltinRedefinition(id.clone()))?
+        }
+
         // Validate premises.
-        for premise in &stmt.premises {
+        for premise in stmt.premises.iter().flat_map(Goal::predicates) {
             self.validate(premise)?;
         }
+        self.validate(&stmt.conclusion)?;
+
+        let pred = self.predicates.get_mut(&id).unwrap();
+        if front {
+            pred.rules.insert(0, (stmt.conclusion, stmt.premises));
+        } else {
+            pred.rules.push((stmt.conclusion, stmt.premises));
+        }
+        Ok(pred)
+    }
+
+    ///
+    /// Removes the first clause which unifies with the given one.
+    /// Returns the removed clause and the unifier if such a clause is found.
+    ///
+    pub fn retract(
+        &mut self,
+        var_alloc: &mut VarAllocator,
+        conclusion: &PredicateObj,
+        premises: &[Goal],
+    ) -> Result<Option<(DefStatement, VarSubstitution)>, ErrorKind> {
+        let Some(pred) = self.predicates.get_mut(&conclusion.id()) else {
+            return Ok(None);
+        };
+        pred.dynamic = true;
+
+        for idx in 0..pred.rules.len() {
+            let (rule_conclusion, rule_premises) = &pred.rules[idx];
+            if rule_premises.len() != premises.len() {
+                continue;
+            }
+
+            // Copy predicate objects to assign IDs.
+            let mut rule_conclusion = rule_conclusion.clone();
+            let mut rule_premises = rule_premises.clone();
+            let mut id_assignments = HashMap::new();
+            var_alloc.assign_new_ids(&mut rule_conclusion.arguments, &mut id_assignments)?;
+            for premise in &mut rule_premises {
+                var_alloc.assign_goal_ids(premise, &mut id_assignments)?;
+            }
+
+            let mut exprs = conclusion
+                .arguments
+                .iter()
+                .cloned()
+                .zip(rule_conclusion.arguments)
+                .collect::<VecDeque<_>>();
+            if !premises
+                .iter()
+                .zip(&rule_premises)
+                .all(|(lhs, rhs)| pair_goals(lhs, rhs, &mut exprs))
+            {
+                continue;
+            }
+            if let Ok(subst) = unify_exprs(&mut exprs) {
+                let (conclusion, premises) = pred.rules.remove(idx);
+                let stmt = DefStatement {
+                    conclusion,
+                    premises,
+                };
+                return Ok(Some((stmt, subst)));
+            }
+        }
+        Ok(None)
+    }
+
+    ///
+    /// Writes the predicates, their clauses and the declarations of the modules as an image.
+    /// The settings such as `--unknown` are not saved since they come from the command line.
+    ///
+    pub fn save_image(&self, writer: impl io::Write) -> io::Result<()> {
+        let mut image = ImageWriter::new();
+        let mut ids = self.predicates.keys().collect::<Vec<_>>();
+        ids.sort();
+        image.write_len(ids.len());
+        for id in ids {
+            let pred = &self.predicates[id];
+            image.write_id(id);
+            image.write_bool(pred.dynamic);
+            image.write_bool(pred.library);
+            image.write_len(pred.rules.len());
+            for (conclusion, premises) in &pred.rules {
+                image.write_predicate(conclusion);
+                image.write_len(premises.len());
+                for premise in premises {
+                    image.write_goal(premise);
+                }
+            }
+        }
+
+        let mut names = self.modules.keys().collect::<Vec<_>>();
+        names.sort();
+        image.write_len(names.len());
+        for name in names {
+            let module = &self.modules[name];
+            image.write_str(name);
+            image.write_len(module.exports.len());
+            for id in &module.exports {
+                image.write_id(id);
+            }
+            image.write_len(module.imports.len());
+            for import in &module.imports {
+                image.write_str(import);
+            }
+        }
+        image.finish(writer)
+    }
+
+    ///
+    /// Replaces the predicates and the modules with the ones saved in the image, keeping the settings.
+    /// The environment is left unchanged if the image is malformed or of another version.
+    ///
+    pub fn load_image(&mut self, bytes: &[u8]) -> Result<(), ErrorKind> {
+        let mut image = ImageReader::new(bytes)?;
+        let mut predicates = HashMap::new();
+        for _ in 0..image.read_len()? {
+            let id = image.read_id()?;
+            let mut pred = Predicate::new();
+            pred.dynamic = image.read_bool()?;
+            pred.library = image.read_bool()?;
+            for _ in 0..image.read_len()? {
+                let conclusion = image.read_predicate()?;
+                let premises = (0..image.read_len()?)
+                    .map(|_| image.read_goal())
+                    .collect::<Result<_, _>>()?;
+                pred.rules.push((conclusion, premises));
+            }
+            predicates.insert(id, pred);
+        }
+
+        let mut modules = HashMap::new();
+        for _ in 0..image.read_len()? {
+            let name = image.read_str()?;
+            let mut module = Module::default();
+            for _ in 0..image.read_len()? {
+                module.exports.push(image.read_id()?);
+            }
+            for _ in 0..image.read_len()? {
+                module.imports.push(image.read_str()?);
+            }
+            modules.insert(name, module);
+        }
+        if !image.is_empty() {
+            Err(ErrorKind::InvalidData("image".to_string()))?
+        }
+
+        self.predicates = predicates;
+        self.modules = modules;
+        self.deferred.clear();
+        Ok(())
+    }
+
+    ///
+    /// Writes the clauses other than the prelude as a program, which restores them when it is run.
+    /// The dynamic predicates are declared by `#dynamic`, since some of them may have no clauses.
+    /// The clauses of a module follow its `#module` with the names in the module unqualified,
+    /// so the modules come after the predicates outside any module.
+    ///
+    pub fn dump(&self) -> String {
+        let mut text = format!("% lonly image version {}\n", IMAGE_VERSION);
+        let mut names = self
+            .modules
+            .keys()
+            .filter(|name| name.as_str() != USER_MODULE)
+            .collect::<Vec<_>>();
+        names.sort();
+        if let Some(user) = self.modules.get(USER_MODULE) {
+            for import in &user.imports {
+                writeln!(text, "#import {}.", import).unwrap();
+            }
+        }
+        self.dump_clauses(&mut text, None);
+
+        for name in names {
+            let module = &self.modules[name];
+            writeln!(text, "\n#module {}.", name).unwrap();
+            if !module.exports.is_empty() {
+                let exports = module.exports.iter().map(|id| id.to_string());
+                writeln!(text, "#export {}.", exports.collect::<Vec<_>>().join(", ")).unwrap();
+            }
+            for import in &module.imports {
+                writeln!(text, "#import {}.", import).unwrap();
+            }
+            self.dump_clauses(&mut text, Some(name));
+        }
+        text
+    }
 
-        let conclusion_len = stmt.conclusion.arguments.len();
-        match self.predicates.get_mut(&stmt.conclusion.name) {
-            Some(pred) => {
-                if conclusion_len != pred.length {
-                    Err(ErrorKind::ArityMismatch(
-                        stmt.conclusion.name.to_string(),
-                        pred.length,
-                        conclusion_len,
-                    ))
-                } else {
-                    pred.rules.push((stmt.conclusion, stmt.premises));
-                    Ok(())
+    fn dump_clauses(&self, text: &mut String, module: Option<&str>) {
+        let prefix = module.map(|module| format!("{}{}", module, MODULE_SEPARATOR));
+        let ids = self
+            .predicate_ids()
+            .into_iter()
+            .filter(|id| !self.is_library(id) && id.split_module().0 == module)
+            .collect::<Vec<_>>();
+        let dynamic = ids
+            .iter()
+            .filter(|id| self.predicates[*id].dynamic)
+            .map(|id| {
+                let name = prefix.as_ref().map_or(id.name.as_str(), |prefix| {
+                    id.name.strip_prefix(prefix.as_str()).unwrap()
+                });
+                format!("{}/{}", name, id.arity)
+            })
+            .collect::<Vec<_>>();
+        if !dynamic.is_empty() {
+            writeln!(text, "#dynamic {}.", dynamic.join(", ")).unwrap();
+        }
+        for id in ids {
+            for (conclusion, premises) in &self.predicates[id].rules {
+                let mut stmt = DefStatement {
+                    conclusion: conclusion.clone(),
+                    premises: premises.clone(),
+                };
+                if let Some(prefix) = &prefix {
+                    let pred_objs = std::iter::once(&mut stmt.conclusion)
+                        .chain(stmt.premises.iter_mut().flat_map(Goal::predicates_mut));
+                    for pred_obj in pred_objs {
+                        self.unqualify(prefix, pred_obj);
+                    }
                 }
+                let stmt = Statement::Def(stmt);
+                writeln!(
+                    text,
+                    "{}",
+                    format_statement(&stmt, Syntax::Terminated, DEFAULT_WIDTH)
+                )
+                .unwrap();
             }
-            None => {
-                let mut pred = Predicate::new(conclusion_len);
-                let name = stmt.conclusion.name.to_string();
-                pred.rules.push((stmt.conclusion, stmt.premises));
-                self.predicates.insert(name, pred);
-                Ok(())
+        }
+    }
+
+    ///
+    /// Removes the prefix of the module from the predicate object and the closures passed to it,
+    /// undoing `Environment::qualify`.
+    ///
+    fn unqualify(&self, prefix: &str, pred_obj: &mut PredicateObj) {
+        let meta = self.meta_arguments(&pred_obj.id());
+        if let Some(name) = pred_obj.name.strip_prefix(prefix) {
+            pred_obj.name = name.into();
+        }
+        for (idx, extra) in meta {
+            let Expr::Atom(closure) = &pred_obj.arguments[idx] else {
+                continue;
+            };
+            let mut goal = PredicateObj::new(closure.name.clone(), closure.arguments.to_vec());
+            if extra == 0 {
+                self.unqualify(prefix, &mut goal);
+            } else if let Some(name) = goal.name.strip_prefix(prefix) {
+                goal.name = name.into();
             }
+            pred_obj.arguments[idx] = AtomExpr::new(goal.name, goal.arguments);
         }
     }
 }
+
+///
+/// Loads the clauses and the directives of a program in the way the REPL does,
+/// skipping the queries and the tests.
+///
+#[cfg(test)]
+pub(crate) fn load_program(env: &mut Environment, program: &str) {
+    run_program(env, program, |_, _, _| {});
+}
+
+///
+/// Loads a program in the way the REPL does,
+/// passing each query and test to `run` with the module it is written in as soon as it is read.
+///
+#[cfg(test)]
+pub(crate) fn run_program(
+    env: &mut Environment,
+    program: &str,
+    mut run: impl FnMut(&mut Environment, &str, Statement),
+) {
+    let (_, statements) =
+        crate::parser::parse_program(nom_locate::LocatedSpan::new(program), Syntax::Terminated)
+            .unwrap();
+    let mut module = USER_MODULE.to_string();
+    for stmt in statements {
+        match stmt {
+            Statement::Def(stmt) => env.define(&module, stmt).unwrap(),
+            Statement::Directive(directive) => env.apply_directive(&mut module, directive).unwrap(),
+            stmt => run(env, &module, stmt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nom_locate::LocatedSpan;
+
+    use super::*;
+    use crate::{
+        ast::Statement,
+        parser::{parse_program, Syntax},
+    };
+
+    #[test]
+    fn check_undefined_test() {
+        let mut env = Environment::new();
+        load_program(
+            &mut env,
+            "num(z).\nnum(s($n)) <- num($n).\nodd(s($n)) <- evn($n).",
+        );
+        assert!(env.is_defined(&PredicateId::new("num".to_string(), 1)));
+        assert!(!env.is_defined(&PredicateId::new("evn".to_string(), 1)));
+        assert_eq!(
+            env.check_undefined(),
+            vec![WarningKind::UndefinedPremise(
+                PredicateId::new("odd".to_string(), 1),
+                PredicateId::new("evn".to_string(), 1)
+            )]
+        );
+    }
+
+    #[test]
+    fn arity_overloading_test() {
+        let mut env = Environment::new();
+        load_program(&mut env, "foo(a).\nfoo(a, b).");
+        assert!(env.is_defined(&PredicateId::new("foo".to_string(), 1)));
+        assert!(env.is_defined(&PredicateId::new("foo".to_string(), 2)));
+
+        let mut env = Environment::new();
+        env.set_strict_arity(true);
+        load_program(&mut env, "foo(a).");
+        let (_, statements) =
+            parse_program(LocatedSpan::new("foo(a, b)."), Syntax::Terminated).unwrap();
+        let Some(Statement::Def(stmt)) = statements.into_iter().next() else {
+            panic!("expected a definition");
+        };
+        assert!(matches!(
+            env.update(stmt),
+            Err(ErrorKind::ArityMismatch(name, 1, 2)) if name == "foo"
+        ));
+    }
+
+    #[test]
+    fn image_test() {
+        let program = "\
+            #import shapes.\n\
+            edge(a, b).\n\
+            path($x, $y) <- (edge($x, $y) ; edge($x, $z), path($z, $y)).\n\
+            #module shapes.\n\
+            #export area/2.\n\
+            helper($x, $y) <- mul($x, $x, $y).\n\
+            area(square($x), $a) <- helper($x, $a).";
+        let mut env = Environment::new();
+        load_program(&mut env, program);
+        let dump = env.dump();
+        assert_eq!(
+            dump,
+            "% lonly image version 1\n\
+            #import shapes.\n\
+            edge(a, b).\n\
+            path($x, $y) <- (edge($x, $y) ; edge($x, $z), path($z, $y)).\n\
+            \n\
+            #module shapes.\n\
+            #export area/2.\n\
+            area(square($x), $a) <- helper($x, $a).\n\
+            helper($x, $y) <- mul($x, $x, $y).\n"
+        );
+
+        // The dump restores the same environment when it is run.
+        let mut restored = Environment::new();
+        load_program(&mut restored, &dump);
+        assert_eq!(restored.dump(), dump);
+
+        let mut image = Vec::new();
+        env.save_image(&mut image).unwrap();
+        let mut restored = Environment::new();
+        load_program(&mut restored, "edge(c, d).");
+        restored.load_image(&image).unwrap();
+        assert_eq!(restored.dump(), dump);
+        assert_eq!(
+            restored.resolve(&PredicateId::new("area".to_string(), 2)),
+            PredicateId::new("shapes:area".to_string(), 2)
+        );
+
+        // A truncated image leaves the environment as it is.
+        let mut other = Environment::new();
+        load_program(&mut other, "edge(c, d).");
+        assert!(matches!(
+            other.load_image(&image[..image.len() - 1]),
+            Err(ErrorKind::InvalidData(_))
+        ));
+        assert_eq!(other.dump(), "% lonly image version 1\nedge(c, d).\n");
+    }
+
+    #[test]
+    fn dump_dynamic_test() {
+        // A dynamic predicate stays dynamic after it loses all its clauses.
+        let mut env = Environment::new();
+        load_program(&mut env, "flag(on).\n#module a.\n#dynamic seen/1.");
+        let mut var_alloc = VarAllocator::default();
+        let flag = PredicateObj::new("flag".to_string(), vec![AtomExpr::new("on", vec![])]);
+        assert!(env.retract(&mut var_alloc, &flag, &[]).unwrap().is_some());
+        let dump = env.dump();
+        assert_eq!(
+            dump,
+            "% lonly image version 1\n#dynamic flag/1.\n\n#module a.\n#dynamic seen/1.\n"
+        );
+        let mut restored = Environment::new();
+        load_program(&mut restored, &dump);
+        assert!(restored.is_defined(&PredicateId::new("flag".to_string(), 1)));
+        assert!(restored.is_defined(&PredicateId::new("a:seen".to_string(), 1)));
+        assert_eq!(restored.dump(), dump);
+    }
+
+    #[test]
+    fn substitute_test() {
+        let ground = AtomExpr::new("s", vec![AtomExpr::new("z", vec![])]);
+        let mut expr = AtomExpr::new("f", vec![ground, VarExpr::new("x")]);
+        let mut assigned = HashMap::new();
+        VarAllocator::new()
+            .assign_new_ids(std::slice::from_mut(&mut expr), &mut assigned)
+            .unwrap();
+
+        let mut subst = VarSubstitution::new();
+        subst.insert(assigned[&Symbol::from("x")], AtomExpr::new("a", vec![]));
+        let mut substituted = expr.clone();
+        subst.substitute(&mut substituted);
+        assert_eq!(substituted.to_string(), "f(s(z), a)");
+
+        // The subterm without variables is shared rather than copied.
+        let (Expr::Atom(before), Expr::Atom(after)) = (&expr, &substituted) else {
+            unreachable!()
+        };
+        let (Expr::Atom(before), Expr::Atom(after)) = (&before.arguments[0], &after.arguments[0])
+        else {
+            unreachable!()
+        };
+        assert!(std::rc::Rc::ptr_eq(&before.arguments, &after.arguments));
+    }
+}
@@ -1,18 +1,389 @@
-use nom::error::VerboseError;
+use std::{error, fmt};
+
+use nom::error::{VerboseError, VerboseErrorKind};
 use nom_locate::LocatedSpan;
 
+use crate::ast::{Expr, PredicateId};
+
 #[derive(Debug)]
 pub enum ErrorKind {
-    Parser(String),
+    /// A syntax error with the text where it is found and the expected tokens.
+    Parser(String, Vec<String>),
     ArityMismatch(String, usize, usize),
     VariableIDAlreadyAssigned(String),
     UnknownInstruction,
+    UnknownCommand(String),
+    InvalidPredicateId(String),
+    UnknownPredicate(PredicateId),
+    NotInstantiated(PredicateId),
+    TypeMismatch(PredicateId, String, Expr),
+    BuiltinRedefinition(PredicateId),
+    PrivatePredicate(PredicateId),
+    ExportOutsideModule,
+    /// An exception raised by `throw/1` which no `catch/3` has caught.
+    Thrown(Expr),
+    /// A file cannot be read or written. The cause is kept as the source of the `Error`.
+    Io(String),
+    /// A file of data such as CSV or JSON is malformed. The cause is kept as the source of the `Error`.
+    InvalidData(String),
+    /// The arguments of a REPL command are wrong. It has the expected form of the command.
+    CommandUsage(String),
+    /// A variable to export is bound to the second element, which is not an atom, or is left unbound.
+    NotExportable(String, Expr),
+    /// An image is written in another version of the format, which is the element.
+    IncompatibleImage(u32),
+    /// The bytecode machine cannot compile a call of the built-in predicate.
+    UnsupportedByMachine(PredicateId),
+}
+
+impl ErrorKind {
+    ///
+    /// Returns the code which identifies the kind of the error in diagnostics.
+    ///
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Parser(_, _) => "E0001",
+            ErrorKind::ArityMismatch(_, _, _) => "E0002",
+            ErrorKind::VariableIDAlreadyAssigned(_) => "E0003",
+            ErrorKind::UnknownInstruction => "E0004",
+            ErrorKind::UnknownCommand(_) => "E0005",
+            ErrorKind::InvalidPredicateId(_) => "E0006",
+            ErrorKind::UnknownPredicate(_) => "E0007",
+            ErrorKind::NotInstantiated(_) => "E0008",
+            ErrorKind::TypeMismatch(_, _, _) => "E0009",
+            ErrorKind::BuiltinRedefinition(_) => "E0010",
+            ErrorKind::PrivatePredicate(_) => "E0011",
+            ErrorKind::ExportOutsideModule => "E0012",
+            ErrorKind::Thrown(_) => "E0013",
+            ErrorKind::Io(_) => "E0014",
+            ErrorKind::InvalidData(_) => "E0015",
+            ErrorKind::CommandUsage(_) => "E0016",
+            ErrorKind::NotExportable(_, _) => "E0017",
+            ErrorKind::IncompatibleImage(_) => "E0018",
+            ErrorKind::UnsupportedByMachine(_) => "E0019",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Parser(text, expected) => {
+                if text.is_empty() {
+                    write!(f, "The program ends unexpectedly")?;
+                } else {
+                    write!(f, "Unexpected \"{}\" in the program", text)?;
+                }
+                if !expected.is_empty() {
+                    write!(f, "; expected {}", expected.join(" or "))?;
+                }
+                write!(f, ".")
+            }
+            ErrorKind::ArityMismatch(name, size1, size2) => write!(
+                f,
+                "Arity of the predicate \"{}\" is expected to be {}, but is {}.",
+                name, size1, size2
+            ),
+            ErrorKind::VariableIDAlreadyAssigned(name) => {
+                write!(f, "The id of variable \"{}\" is already assigned.", name)
+            }
+            ErrorKind::UnknownInstruction => write!(f, "This option is not supported."),
+            ErrorKind::UnknownCommand(name) => {
+                write!(f, "The command \":{}\" is not supported.", name)
+            }
+            ErrorKind::InvalidPredicateId(text) => {
+                write!(f, "\"{}\" is not of the form \"name/arity\".", text)
+            }
+            ErrorKind::UnknownPredicate(id) => {
+                write!(f, "The predicate \"{}\" has no clauses.", id)
+            }
+            ErrorKind::NotInstantiated(id) => write!(
+                f,
+                "The arguments of \"{}\" are not sufficiently instantiated.",
+                id
+            ),
+            ErrorKind::TypeMismatch(id, expected, culprit) => {
+                write!(
+                    f,
+                    "\"{}\" expects {}, but got \"{}\".",
+                    id, expected, culprit
+                )
+            }
+            ErrorKind::BuiltinRedefinition(id) => {
+                write!(f, "The built-in predicate \"{}\" cannot be redefined.", id)
+            }
+            ErrorKind::PrivatePredicate(id) => {
+                write!(f, "The predicate \"{}\" is not exported by its module.", id)
+            }
+            ErrorKind::ExportOutsideModule => write!(f, "\"#export\" must follow \"#module\"."),
+            ErrorKind::Thrown(ball) => write!(f, "Uncaught exception: {}", ball),
+            ErrorKind::Io(path) => write!(f, "Failed to access \"{}\".", path),
+            ErrorKind::InvalidData(format) => write!(f, "The {} data is malformed.", format),
+            ErrorKind::CommandUsage(usage) => write!(f, "Usage: {}", usage),
+            ErrorKind::IncompatibleImage(version) => write!(
+                f,
+                "The image is written in version {} of the format, but this build reads version {}.",
+                version,
+                crate::image::IMAGE_VERSION
+            ),
+            ErrorKind::UnsupportedByMachine(id) => write!(
+                f,
+                "The built-in predicate \"{}\" is not supported by the bytecode machine.",
+                id
+            ),
+            ErrorKind::NotExportable(name, Expr::Var(_)) => {
+                write!(
+                    f,
+                    "The variable \"${}\" is left unbound in an answer.",
+                    name
+                )
+            }
+            ErrorKind::NotExportable(name, expr) => write!(
+                f,
+                "The variable \"${}\" is bound to \"{}\", which is not an atom.",
+                name, expr
+            ),
+        }
+    }
+}
+
+impl error::Error for ErrorKind {}
+
+///
+/// A position in the source text. Lines and columns are counted from 1.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub offset: usize,
+    pub line: u32,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(text: &LocatedSpan<&str>) -> Self {
+        Span {
+            offset: text.location_offset(),
+            line: text.location_line(),
+            column: text.get_utf8_column(),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+///
+/// An error with the position where it is detected and the error which caused it.
+/// The details are boxed to keep `Result<T, Error>` small.
+///
+#[derive(Debug)]
+pub struct Error {
+    inner: Box<ErrorInner>,
+}
+
+#[derive(Debug)]
+struct ErrorInner {
+    kind: ErrorKind,
+    span: Option<Span>,
+    source: Option<Box<dyn error::Error + Send + Sync>>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            inner: Box::new(ErrorInner {
+                kind,
+                span: None,
+                source: None,
+            }),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.inner.span = Some(span);
+        self
+    }
+
+    pub fn with_source(mut self, source: impl Into<Box<dyn error::Error + Send + Sync>>) -> Self {
+        self.inner.source = Some(source.into());
+        self
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.inner.kind
+    }
+
+    pub fn into_kind(self) -> ErrorKind {
+        self.inner.kind
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.inner.kind.code()
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.inner.span
+    }
+}
+
+///
+/// Writes `[code] message (line L, column C) Detail: cause`,
+/// where the position and the cause are omitted if unknown.
+///
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code(), self.kind())?;
+        if let Some(span) = self.span() {
+            write!(f, " ({})", span)?;
+        }
+        if let Some(source) = &self.inner.source {
+            write!(f, " Detail: {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.inner
+            .source
+            .as_deref()
+            .map(|source| source as &(dyn error::Error + 'static))
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WarningKind {
+    /// A premise of the clause whose head is the first element refers to a predicate with no clauses.
+    UndefinedPremise(PredicateId, PredicateId),
+    /// A goal called a predicate with no clauses at runtime.
+    UnknownPredicate(PredicateId),
+    /// A variable appears only once in a clause, which is often a typo.
+    SingletonVariable(String),
+}
+
+impl WarningKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            WarningKind::UndefinedPremise(_, _) => "W0001",
+            WarningKind::UnknownPredicate(_) => "W0002",
+            WarningKind::SingletonVariable(_) => "W0003",
+        }
+    }
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WarningKind::UndefinedPremise(id, premise) => write!(
+                f,
+                "A clause of \"{}\" refers to \"{}\", which has no clauses.",
+                id, premise
+            ),
+            WarningKind::UnknownPredicate(id) => write!(
+                f,
+                "The predicate \"{}\" has no clauses, so the goal fails.",
+                id
+            ),
+            WarningKind::SingletonVariable(name) => write!(
+                f,
+                "The variable \"${}\" appears only once in the clause.",
+                name
+            ),
+        }
+    }
 }
 
 type NomErr<'a> = nom::Err<VerboseError<LocatedSpan<&'a str>>>;
 
-impl<'a> From<NomErr<'a>> for ErrorKind {
+impl<'a> From<NomErr<'a>> for Error {
     fn from(value: NomErr<'a>) -> Self {
-        ErrorKind::Parser(value.to_string())
+        let err = match value {
+            nom::Err::Error(err) | nom::Err::Failure(err) => err,
+            nom::Err::Incomplete(_) => return Error::new(ErrorKind::Parser(String::new(), vec![])),
+        };
+
+        // The first error is the innermost one, where the parser stopped.
+        // The innermost context tells what is expected there.
+        let Some((text, _)) = err.errors.first() else {
+            return Error::new(ErrorKind::Parser(String::new(), vec![]));
+        };
+        let snippet = text
+            .fragment()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(20)
+            .collect();
+        let expected = err
+            .errors
+            .iter()
+            .find_map(|(_, kind)| match kind {
+                VerboseErrorKind::Context(expected) => Some(expected.to_string()),
+                _ => None,
+            })
+            .into_iter()
+            .collect();
+        Error::new(ErrorKind::Parser(snippet, expected)).with_span(Span::new(text))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{parse_program, Syntax};
+
+    #[test]
+    fn error_display_test() {
+        let err = Error::from(ErrorKind::UnknownCommand("foo".to_string()));
+        assert_eq!(
+            err.to_string(),
+            "[E0005] The command \":foo\" is not supported."
+        );
+
+        let program = "num(z).\nnum(s($n) <- num($n).";
+        let parsed = parse_program(LocatedSpan::new(program), Syntax::Terminated);
+        let err = Error::from(parsed.unwrap_err());
+        assert_eq!(err.code(), "E0001");
+        assert_eq!(err.span().map(|span| span.line), Some(2));
+
+        let io_err = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let err = Error::new(ErrorKind::Io("a.lonly".to_string())).with_source(io_err);
+        assert_eq!(
+            err.to_string(),
+            "[E0014] Failed to access \"a.lonly\". Detail: missing"
+        );
+        assert!(error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn error_kind_test() {
+        fn define(program: &str) -> Result<(), Box<dyn error::Error>> {
+            let (_, statements) = parse_program(LocatedSpan::new(program), Syntax::Terminated)
+                .map_err(Error::from)?;
+            let mut env = crate::env::Environment::new();
+            for stmt in statements {
+                if let crate::ast::Statement::Def(stmt) = stmt {
+                    env.define(crate::ast::USER_MODULE, stmt)?;
+                }
+            }
+            Ok(())
+        }
+        assert!(define("p(a).").is_ok());
+        assert_eq!(
+            define("dif($x, $y).").unwrap_err().to_string(),
+            ErrorKind::BuiltinRedefinition(PredicateId::new("dif".to_string(), 2)).to_string()
+        );
+        assert!(define("p(").is_err());
     }
 }
@@ -1,127 +1,655 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    rc::Rc,
+};
 
 use crate::{
-    ast::{PredicateObj, VarID},
-    env::{Environment, VarAllocator, VarSubstitution},
-    error::ErrorKind,
-    unifier::unify_exprs,
+    ast::{DefStatement, Expr, Goal, PredicateId, PredicateObj, VarID},
+    builtins::{
+        clear_var_ids, collect_vars, compare_terms, error_term, expr_to_int, expr_to_predicate,
+        int_to_expr, Builtin,
+    },
+    env::{Environment, UnknownMode, VarAllocator, VarSubstitution},
+    error::{ErrorKind, WarningKind},
+    symbol::Symbol,
+    unifier::{identical, unify_exprs},
 };
 
 pub struct Goals {
-    goals: VecDeque<PredicateObj>,
+    goals: VecDeque<Goal>,
     resolved_vars: VarSubstitution,
+    /// Pairs of expressions delayed by `dif/2`.
+    constraints: Vec<(Expr, Expr)>,
+    /// The `catch/3` calls which are still running, the innermost last.
+    catches: Vec<Rc<CatchFrame>>,
+}
+
+///
+/// Records a call of `catch/3` to restart from it when an exception is thrown.
+///
+struct CatchFrame {
+    catcher: Expr,
+    recovery: Expr,
+    /// The state after the call of `catch/3`.
+    /// The goal of `catch/3` is finished when only these goals remain.
+    continuation: Goals,
+}
+
+///
+/// The result of examining a `dif/2` constraint.
+///
+enum DifStatus {
+    Satisfied,
+    Violated,
+    Suspended,
+}
+
+fn instantiate(expr: &Expr, subst: &VarSubstitution) -> Expr {
+    let mut expr = expr.clone();
+    subst.substitute(&mut expr);
+    expr
+}
+
+///
+/// Sorts the terms in the standard order and removes the duplicates.
+///
+fn sort_terms(items: &mut Vec<Expr>) {
+    items.sort_by(compare_terms);
+    items.dedup_by(|lhs, rhs| identical(lhs, rhs));
+}
+
+fn check_dif(lhs: &Expr, rhs: &Expr) -> DifStatus {
+    let mut exprs = VecDeque::from([(lhs.clone(), rhs.clone())]);
+    match unify_exprs(&mut exprs) {
+        Err(_) => DifStatus::Satisfied,
+        Ok(subst) if subst.is_empty() => DifStatus::Violated,
+        Ok(_) => DifStatus::Suspended,
+    }
 }
 
 impl Goals {
     pub fn new(
         goal: &mut PredicateObj,
         var_alloc: &mut VarAllocator,
-    ) -> Result<(Self, HashMap<String, u32>), ErrorKind> {
+    ) -> Result<(Self, HashMap<Symbol, VarID>), ErrorKind> {
         let mut id_assignments = HashMap::new();
         var_alloc.assign_new_ids(&mut goal.arguments, &mut id_assignments)?;
 
         let mut goals = VecDeque::new();
-        goals.push_back(goal.clone());
+        goals.push_back(Goal::Pred(goal.clone()));
         Ok((
             Self {
                 goals,
                 resolved_vars: VarSubstitution::new(),
+                constraints: Vec::new(),
+                catches: Vec::new(),
             },
             id_assignments,
         ))
     }
 
+    ///
+    /// Returns the first goal with the resolved variables substituted.
+    ///
+    fn first_goal(&self) -> Option<Goal> {
+        let mut goal = self.goals.front()?.clone();
+        self.resolved_vars.substitute_goal(&mut goal);
+        Some(goal)
+    }
+
+    ///
+    /// Replaces the first goal with `premises` after applying `new_subst`.
+    /// The variables in the goals are substituted when they are taken out by `first_goal`.
+    ///
+    fn replace_first(&self, new_subst: &VarSubstitution, mut premises: VecDeque<Goal>) -> Goals {
+        let mut subst = self.resolved_vars.clone();
+        subst.merge(new_subst);
+
+        let mut goals = self.goals.clone();
+        goals.pop_front();
+        premises.append(&mut goals);
+
+        Goals {
+            goals: premises,
+            resolved_vars: subst,
+            constraints: self.constraints.clone(),
+            catches: self.catches.clone(),
+        }
+    }
+
+    ///
+    /// Drops the `catch/3` calls whose goals have been finished.
+    ///
+    fn exit_catches(&mut self) {
+        while let Some(frame) = self.catches.last() {
+            if self.goals.len() > frame.continuation.goals.len() {
+                break;
+            }
+            self.catches.pop();
+        }
+    }
+
+    ///
+    /// Re-examines the delayed constraints under the resolved variables.
+    /// Returns false if one of them is violated.
+    ///
+    fn check_constraints(&mut self) -> bool {
+        let mut remaining = Vec::new();
+        for (mut lhs, mut rhs) in std::mem::take(&mut self.constraints) {
+            self.resolved_vars.substitute(&mut lhs);
+            self.resolved_vars.substitute(&mut rhs);
+            match check_dif(&lhs, &rhs) {
+                DifStatus::Satisfied => {}
+                DifStatus::Violated => return false,
+                DifStatus::Suspended => remaining.push((lhs, rhs)),
+            }
+        }
+        self.constraints = remaining;
+        true
+    }
+
+    ///
+    /// Removes the first goal after unifying the pairs of expressions.
+    /// Returns `None` if they do not unify.
+    ///
+    fn unify_first(&self, mut exprs: VecDeque<(Expr, Expr)>) -> Option<Goals> {
+        let subst = unify_exprs(&mut exprs).ok()?;
+        Some(self.replace_first(&subst, VecDeque::new()))
+    }
+
     pub fn apply_rule(
         &self,
         var_alloc: &mut VarAllocator,
+        goal: &PredicateObj,
         conclusion: &PredicateObj,
-        premises: &Vec<PredicateObj>,
+        premises: &[Goal],
     ) -> Result<Option<Goals>, ErrorKind> {
-        let mut goals = self.goals.clone();
-        if let Some(goal) = goals.pop_front() {
-            // Copy predicate objects to assign IDs.
-            let mut conclusion = conclusion.clone();
-            let mut premises: VecDeque<_> = premises.clone().into();
-            let mut subst = self.resolved_vars.clone();
-
-            // Assignment new variable IDs.
-            let mut id_assignments = HashMap::new();
-            var_alloc.assign_new_ids(&mut conclusion.arguments, &mut id_assignments)?;
-            for premise in &mut premises {
-                var_alloc.assign_new_ids(&mut premise.arguments, &mut id_assignments)?;
-            }
-
-            if goal.arguments.len() != conclusion.arguments.len() {
-                return Ok(None);
-            }
-
-            // Applying the rule by unifying variables.
-            let mut exprs = VecDeque::new();
-            for idx in 0..goal.arguments.len() {
-                exprs.push_back((
-                    goal.arguments[idx].clone(),
-                    conclusion.arguments[idx].clone(),
-                ));
-            }
-            match unify_exprs(&mut exprs) {
-                Ok(new_subst) => {
-                    subst.merge(&new_subst);
-
-                    // Replace variables with the solutions.
-                    for premise in &mut premises {
-                        for arg in &mut premise.arguments {
-                            subst.substitute(arg);
-                        }
-                    }
+        // Copy predicate objects to assign IDs.
+        let mut conclusion = conclusion.clone();
+        let mut premises: VecDeque<_> = premises.iter().cloned().collect();
 
-                    // Replace the first goal with premises.
-                    let mut new_goals = premises;
-                    new_goals.append(&mut goals);
+        // Assignment new variable IDs.
+        let mut id_assignments = HashMap::new();
+        var_alloc.assign_new_ids(&mut conclusion.arguments, &mut id_assignments)?;
+        for premise in &mut premises {
+            var_alloc.assign_goal_ids(premise, &mut id_assignments)?;
+        }
 
-                    Ok(Some(Goals {
-                        goals: new_goals,
-                        resolved_vars: subst,
-                    }))
-                }
-                Err(_) => Ok(None),
-            }
-        } else {
-            Ok(None)
+        if goal.arguments.len() != conclusion.arguments.len() {
+            return Ok(None);
+        }
+
+        // Applying the rule by unifying variables.
+        let mut exprs = goal
+            .arguments
+            .iter()
+            .cloned()
+            .zip(conclusion.arguments)
+            .collect::<VecDeque<_>>();
+        match unify_exprs(&mut exprs) {
+            Ok(new_subst) => Ok(Some(self.replace_first(&new_subst, premises))),
+            Err(_) => Ok(None),
         }
     }
 }
 
+///
+/// An engine which enumerates the answers of a query one by one.
+/// It is implemented by the interpreter and by the bytecode machine of `crate::vm`.
+///
+pub trait Solver {
+    fn next(&mut self) -> Result<Option<VarSubstitution>, ErrorKind>;
+
+    fn take_warnings(&mut self) -> Vec<WarningKind>;
+
+    fn residual_goals(&self) -> Vec<PredicateObj>;
+}
+
+impl Solver for SolutionGenerator<'_> {
+    fn next(&mut self) -> Result<Option<VarSubstitution>, ErrorKind> {
+        SolutionGenerator::next(self)
+    }
+
+    fn take_warnings(&mut self) -> Vec<WarningKind> {
+        SolutionGenerator::take_warnings(self)
+    }
+
+    fn residual_goals(&self) -> Vec<PredicateObj> {
+        SolutionGenerator::residual_goals(self)
+    }
+}
+
 pub struct SolutionGenerator<'a> {
     status: VecDeque<Goals>,
-    env: &'a Environment,
+    residual: Vec<(Expr, Expr)>,
+    env: &'a mut Environment,
     var_alloc: VarAllocator,
+    warnings: Vec<WarningKind>,
+    reported: HashSet<PredicateId>,
 }
 
 impl<'a> SolutionGenerator<'a> {
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Option<VarSubstitution>, ErrorKind> {
-        if let Some(state) = self.status.pop_front() {
-            if state.goals.len() == 0 {
-                Ok(Some(state.resolved_vars))
-            } else {
-                if let Some(rules) = self.env.get_rules(&state.goals[0].name) {
-                    for (conclusion, premises) in rules {
-                        let new_goals =
-                            state.apply_rule(&mut self.var_alloc, conclusion, premises)?;
-                        if let Some(new_goals) = new_goals {
-                            self.status.push_back(new_goals);
+        let mut status = std::mem::take(&mut self.status);
+        let solution = self.run(&mut status);
+        self.status = status;
+        Ok(solution?.map(|state| {
+            self.residual = state.constraints;
+            state.resolved_vars
+        }))
+    }
+
+    ///
+    /// Returns the `dif/2` goals which were still suspended in the last solution.
+    ///
+    pub fn residual_goals(&self) -> Vec<PredicateObj> {
+        self.residual
+            .iter()
+            .map(|(lhs, rhs)| PredicateObj::new("dif", vec![lhs.clone(), rhs.clone()]))
+            .collect()
+    }
+
+    ///
+    /// Searches the states in breadth-first order until a solution is found.
+    ///
+    fn run(&mut self, status: &mut VecDeque<Goals>) -> Result<Option<Goals>, ErrorKind> {
+        while let Some(mut state) = status.pop_front() {
+            state.exit_catches();
+            let catches = state.catches.clone();
+            match self.step(state, status) {
+                Ok(Some(solution)) => return Ok(Some(solution)),
+                Ok(None) => {}
+                Err(err) => self.recover(&catches, err, status)?,
+            }
+        }
+        Ok(None)
+    }
+
+    ///
+    /// Restarts from the innermost `catch/3` whose catcher unifies with the exception.
+    /// The other states under the abandoned calls are discarded.
+    /// Returns the error if no catcher matches.
+    ///
+    fn recover(
+        &mut self,
+        catches: &[Rc<CatchFrame>],
+        err: ErrorKind,
+        status: &mut VecDeque<Goals>,
+    ) -> Result<(), ErrorKind> {
+        let Some(ball) = error_term(&err) else {
+            return Err(err);
+        };
+        for frame in catches.iter().rev() {
+            status.retain(|state| !state.catches.iter().any(|other| Rc::ptr_eq(other, frame)));
+
+            let continuation = &frame.continuation;
+            let catcher = instantiate(&frame.catcher, &continuation.resolved_vars);
+            let mut exprs = VecDeque::from([(catcher, ball.clone())]);
+            let Ok(subst) = unify_exprs(&mut exprs) else {
+                continue;
+            };
+
+            let mut recovery = instantiate(&frame.recovery, &continuation.resolved_vars);
+            subst.substitute(&mut recovery);
+            let id = PredicateId::new("catch".to_string(), 3);
+            let recovery = Goal::Pred(expr_to_predicate(&recovery, &id)?);
+
+            let mut resolved_vars = continuation.resolved_vars.clone();
+            resolved_vars.merge(&subst);
+            let mut goals = continuation.goals.clone();
+            goals.push_front(recovery);
+            let state = Goals {
+                goals,
+                resolved_vars,
+                constraints: continuation.constraints.clone(),
+                catches: continuation.catches.clone(),
+            };
+            status.push_back(state);
+            return Ok(());
+        }
+        Err(err)
+    }
+
+    ///
+    /// Resolves the first goal of `state` and pushes the resulting states into `status`.
+    /// Returns the state itself if no goal remains.
+    ///
+    fn step(
+        &mut self,
+        mut state: Goals,
+        status: &mut VecDeque<Goals>,
+    ) -> Result<Option<Goals>, ErrorKind> {
+        if !state.check_constraints() {
+            return Ok(None);
+        }
+        let Some(goal) = state.first_goal() else {
+            return Ok(Some(state));
+        };
+
+        let no_subst = VarSubstitution::new();
+        match goal {
+            Goal::Pred(goal) => self.call_predicate(&state, goal, status)?,
+            Goal::And(goals) => {
+                status.push_back(state.replace_first(&no_subst, goals.into()));
+            }
+            Goal::Or(goals) => {
+                for goal in goals {
+                    status.push_back(state.replace_first(&no_subst, VecDeque::from([goal])));
+                }
+            }
+            Goal::IfThenElse(cond, then, otherwise) => {
+                // Only the first solution of the condition is taken.
+                // Exceptions in the condition are handled by the catches of this state.
+                let mut cond_status = VecDeque::from([Goals {
+                    goals: VecDeque::from([*cond]),
+                    resolved_vars: state.resolved_vars.clone(),
+                    constraints: state.constraints.clone(),
+                    catches: Vec::new(),
+                }]);
+                match self.run(&mut cond_status)? {
+                    Some(solved) => {
+                        let mut goals = state.goals.clone();
+                        goals.pop_front();
+                        goals.push_front(*then);
+                        status.push_back(Goals {
+                            goals,
+                            resolved_vars: solved.resolved_vars,
+                            constraints: solved.constraints,
+                            catches: state.catches.clone(),
+                        });
+                    }
+                    None => {
+                        if let Some(otherwise) = otherwise {
+                            status.push_back(
+                                state.replace_first(&no_subst, VecDeque::from([*otherwise])),
+                            );
                         }
                     }
                 }
-                self.next()
             }
-        } else {
-            Ok(None)
         }
+        Ok(None)
+    }
+
+    fn call_predicate(
+        &mut self,
+        state: &Goals,
+        mut goal: PredicateObj,
+        status: &mut VecDeque<Goals>,
+    ) -> Result<(), ErrorKind> {
+        // Unqualified names in a module may refer to imported or global predicates.
+        let id = self.env.resolve(&goal.id());
+        goal.name = id.name.clone();
+        if let Some(builtin) = Builtin::lookup(&id) {
+            return self.call_builtin(state, builtin, goal, status);
+        }
+
+        if !self.env.is_defined(&id) {
+            match self.env.unknown() {
+                UnknownMode::Fail => {}
+                UnknownMode::Warning => {
+                    if self.reported.insert(id.clone()) {
+                        self.warnings
+                            .push(WarningKind::UnknownPredicate(id.clone()));
+                    }
+                }
+                UnknownMode::Error => Err(ErrorKind::UnknownPredicate(id.clone()))?,
+            }
+        }
+        self.env.check_calls(&id)?;
+        if let Some(rules) = self.env.get_rules(&id) {
+            for (conclusion, premises) in rules {
+                let new_goals =
+                    state.apply_rule(&mut self.var_alloc, &goal, conclusion, premises)?;
+                if let Some(new_goals) = new_goals {
+                    status.push_back(new_goals);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call_builtin(
+        &mut self,
+        state: &Goals,
+        builtin: Builtin,
+        goal: PredicateObj,
+        status: &mut VecDeque<Goals>,
+    ) -> Result<(), ErrorKind> {
+        let id = goal.id();
+        let no_subst = VarSubstitution::new();
+        match builtin {
+            Builtin::AssertA | Builtin::AssertZ => {
+                let mut args = goal.arguments.iter().map(clear_var_ids);
+                let conclusion = expr_to_predicate(&args.next().unwrap(), &id)?;
+                let premises = args
+                    .map(|arg| expr_to_predicate(&arg, &id).map(Goal::Pred))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let stmt = DefStatement {
+                    conclusion,
+                    premises,
+                };
+                if builtin == Builtin::AssertA {
+                    self.env.asserta(stmt)?;
+                } else {
+                    self.env.assertz(stmt)?;
+                }
+                status.push_back(state.replace_first(&no_subst, VecDeque::new()));
+            }
+            Builtin::Retract => {
+                let mut args = goal.arguments.iter();
+                let conclusion = expr_to_predicate(args.next().unwrap(), &id)?;
+                let premises = args
+                    .map(|arg| expr_to_predicate(arg, &id).map(Goal::Pred))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some((_, subst)) =
+                    self.env
+                        .retract(&mut self.var_alloc, &conclusion, &premises)?
+                {
+                    status.push_back(state.replace_first(&subst, VecDeque::new()));
+                }
+            }
+            Builtin::Unify => {
+                let [lhs, rhs]: [Expr; 2] = goal.arguments.try_into().unwrap();
+                status.extend(state.unify_first(VecDeque::from([(lhs, rhs)])));
+            }
+            Builtin::NotUnifiable => {
+                let [lhs, rhs]: [Expr; 2] = goal.arguments.try_into().unwrap();
+                let mut exprs = VecDeque::from([(lhs, rhs)]);
+                if unify_exprs(&mut exprs).is_err() {
+                    status.push_back(state.replace_first(&no_subst, VecDeque::new()));
+                }
+            }
+            Builtin::Identical | Builtin::NotIdentical => {
+                let is_identical = identical(&goal.arguments[0], &goal.arguments[1]);
+                if is_identical == (builtin == Builtin::Identical) {
+                    status.push_back(state.replace_first(&no_subst, VecDeque::new()));
+                }
+            }
+            Builtin::Dif => {
+                let (lhs, rhs) = (&goal.arguments[0], &goal.arguments[1]);
+                let mut new_state = state.replace_first(&no_subst, VecDeque::new());
+                match check_dif(lhs, rhs) {
+                    DifStatus::Satisfied => status.push_back(new_state),
+                    DifStatus::Violated => {}
+                    DifStatus::Suspended => {
+                        new_state.constraints.push((lhs.clone(), rhs.clone()));
+                        status.push_back(new_state);
+                    }
+                }
+            }
+            Builtin::Call => {
+                let mut args = goal.arguments.into_iter();
+                let mut callee = expr_to_predicate(&args.next().unwrap(), &id)?;
+                callee.arguments.extend(args);
+                let premises = VecDeque::from([Goal::Pred(callee)]);
+                status.push_back(state.replace_first(&no_subst, premises));
+            }
+            Builtin::Throw => {
+                let [ball]: [Expr; 1] = goal.arguments.try_into().unwrap();
+                if let Expr::Var(_) = ball {
+                    Err(ErrorKind::NotInstantiated(id.clone()))?
+                }
+                Err(ErrorKind::Thrown(ball))?
+            }
+            Builtin::Catch => {
+                let [sub_goal, catcher, recovery]: [Expr; 3] = goal.arguments.try_into().unwrap();
+                let sub_goal = Goal::Pred(expr_to_predicate(&sub_goal, &id)?);
+                let frame = CatchFrame {
+                    catcher,
+                    recovery,
+                    continuation: state.replace_first(&no_subst, VecDeque::new()),
+                };
+                let mut new_state = state.replace_first(&no_subst, VecDeque::from([sub_goal]));
+                new_state.catches.push(Rc::new(frame));
+                status.push_back(new_state);
+            }
+            Builtin::FindAll => {
+                let [template, sub_goal, result]: [Expr; 3] = goal.arguments.try_into().unwrap();
+                let sub_goal = Goal::Pred(expr_to_predicate(&sub_goal, &id)?);
+                let items = self
+                    .find_all(state, sub_goal)?
+                    .iter()
+                    .map(|solution| instantiate(&template, solution))
+                    .collect();
+                let list = Expr::list(items, Expr::nil());
+                status.extend(state.unify_first(VecDeque::from([(result, list)])));
+            }
+            Builtin::BagOf | Builtin::SetOf => {
+                let [template, sub_goal, result]: [Expr; 3] = goal.arguments.try_into().unwrap();
+
+                // The solutions are grouped by the bindings of the free variables,
+                // which appear in the goal but not in the template.
+                let mut template_vars = Vec::new();
+                collect_vars(&template, &mut template_vars);
+                let mut free_vars = Vec::new();
+                collect_vars(&sub_goal, &mut free_vars);
+                free_vars.retain(|var| !template_vars.contains(var));
+                let witness = Expr::list(free_vars, Expr::nil());
+
+                let sub_goal = Goal::Pred(expr_to_predicate(&sub_goal, &id)?);
+                let mut groups: Vec<(Expr, Vec<Expr>)> = Vec::new();
+                for solution in self.find_all(state, sub_goal)? {
+                    let key = instantiate(&witness, &solution);
+                    let item = instantiate(&template, &solution);
+                    match groups.iter_mut().find(|(other, _)| identical(other, &key)) {
+                        Some((_, items)) => items.push(item),
+                        None => groups.push((key, vec![item])),
+                    }
+                }
+                groups.sort_by(|(lhs, _), (rhs, _)| compare_terms(lhs, rhs));
+
+                for (key, mut items) in groups {
+                    if builtin == Builtin::SetOf {
+                        sort_terms(&mut items);
+                    }
+                    let list = Expr::list(items, Expr::nil());
+                    let exprs = VecDeque::from([(witness.clone(), key), (result.clone(), list)]);
+                    status.extend(state.unify_first(exprs));
+                }
+            }
+            Builtin::AggregateAll => {
+                let [spec, sub_goal, result]: [Expr; 3] = goal.arguments.try_into().unwrap();
+                let (kind, template) = match &spec {
+                    Expr::Atom(atom) if atom.name == "count" && atom.arguments.is_empty() => {
+                        ("count", Expr::nil())
+                    }
+                    Expr::Atom(atom)
+                        if atom.arguments.len() == 1
+                            && ["sum", "max", "min", "bag", "set"]
+                                .contains(&atom.name.as_str()) =>
+                    {
+                        (atom.name.as_str(), atom.arguments[0].clone())
+                    }
+                    Expr::Var(_) => Err(ErrorKind::NotInstantiated(id.clone()))?,
+                    _ => Err(ErrorKind::TypeMismatch(
+                        id.clone(),
+                        "aggregation".to_string(),
+                        spec.clone(),
+                    ))?,
+                };
+
+                let sub_goal = Goal::Pred(expr_to_predicate(&sub_goal, &id)?);
+                let mut items = self
+                    .find_all(state, sub_goal)?
+                    .iter()
+                    .map(|solution| instantiate(&template, solution))
+                    .collect::<Vec<_>>();
+                let value = match kind {
+                    "count" => Some(int_to_expr(items.len() as i64)),
+                    "bag" => Some(Expr::list(items, Expr::nil())),
+                    "set" => {
+                        sort_terms(&mut items);
+                        Some(Expr::list(items, Expr::nil()))
+                    }
+                    _ => {
+                        let values = items
+                            .iter()
+                            .map(|item| expr_to_int(item, &id))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        match kind {
+                            "sum" => Some(values.into_iter().sum()),
+                            "max" => values.into_iter().max(),
+                            _ => values.into_iter().min(),
+                        }
+                        .map(int_to_expr)
+                    }
+                };
+                if let Some(value) = value {
+                    status.extend(state.unify_first(VecDeque::from([(result, value)])));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Collects all the solutions of `goal` under the bindings of `state`
+    /// by running a nested generator on the same environment.
+    ///
+    fn find_all(&mut self, state: &Goals, goal: Goal) -> Result<Vec<VarSubstitution>, ErrorKind> {
+        let nested_state = Goals {
+            goals: VecDeque::from([goal]),
+            resolved_vars: state.resolved_vars.clone(),
+            constraints: state.constraints.clone(),
+            catches: Vec::new(),
+        };
+        let mut nested = SolutionGenerator {
+            status: VecDeque::from([nested_state]),
+            residual: Vec::new(),
+            env: &mut *self.env,
+            var_alloc: std::mem::replace(&mut self.var_alloc, VarAllocator::new()),
+            warnings: Vec::new(),
+            reported: std::mem::take(&mut self.reported),
+        };
+
+        let mut solutions = Vec::new();
+        let result = loop {
+            match nested.next() {
+                Ok(Some(solution)) => solutions.push(solution),
+                Ok(None) => break Ok(solutions),
+                Err(err) => break Err(err),
+            }
+        };
+
+        // The variable IDs must stay unique across the generators.
+        self.var_alloc = nested.var_alloc;
+        self.reported = nested.reported;
+        self.warnings.append(&mut nested.warnings);
+        result
+    }
+
+    ///
+    /// Takes the warnings reported since the last call.
+    ///
+    pub fn take_warnings(&mut self) -> Vec<WarningKind> {
+        std::mem::take(&mut self.warnings)
     }
 
     pub fn new(
         query: &mut PredicateObj,
-        env: &'a Environment,
+        env: &'a mut Environment,
     ) -> Result<(Self, Vec<(String, VarID)>), ErrorKind> {
         let mut var_alloc = VarAllocator::new();
         let (goal, name_table) = Goals::new(query, &mut var_alloc)?;
@@ -130,10 +658,293 @@ impl<'a> SolutionGenerator<'a> {
         Ok((
             SolutionGenerator {
                 status: queue,
+                residual: Vec::new(),
                 var_alloc,
                 env,
+                warnings: Vec::new(),
+                reported: HashSet::new(),
             },
-            name_table.into_iter().collect(),
+            name_table
+                .into_iter()
+                .map(|(name, id)| (name.to_string(), id))
+                .collect(),
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use nom_locate::LocatedSpan;
+
+    use super::*;
+    use crate::{
+        ast::{Statement, USER_MODULE},
+        env::run_program,
+        parser::{parse_program, Syntax},
+        prelude::load_prelude,
+    };
+
+    fn answers(env: &mut Environment, program: &str) -> Vec<Vec<String>> {
+        let mut answers = Vec::new();
+        run_program(env, program, |env, module, stmt| {
+            let Statement::Query(mut stmt) = stmt else {
+                return;
+            };
+            env.qualify(module, &mut stmt.query).unwrap();
+            let (mut solution_gen, mut name_table) =
+                SolutionGenerator::new(&mut stmt.query, env).unwrap();
+            name_table.sort();
+            while let Some(solution) = solution_gen.next().unwrap() {
+                answers.push(
+                    name_table
+                        .iter()
+                        .map(|(name, id)| match solution.get(*id) {
+                            Some(expr) => format!("{} = {}", name, expr),
+                            None => format!("{} = ${}", name, name),
+                        })
+                        .collect(),
+                );
+            }
+        });
+        answers
+    }
+
+    #[test]
+    fn bindings_test() {
+        let mut env = Environment::new();
+        let program = "q(a).\nq(b).\nr(b).\np($x) <- q($x), r($x).\n?p($y).";
+        assert_eq!(answers(&mut env, program), vec![vec!["y = b"]]);
+    }
+
+    #[test]
+    fn disjunction_test() {
+        let mut env = Environment::new();
+        let program = "p(a).\nq(b).\nr(c).\npq($x) <- p($x) ; q($x), (r($x) ; p($y)).\n?pq($z).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![vec!["z = a"], vec!["z = b"]]
+        );
+    }
+
+    #[test]
+    fn if_then_else_test() {
+        let mut env = Environment::new();
+        let program = "p(a).\np(b).\nfirst($x) <- (p($x) -> true ; none($x)).\nnone(z).\ntrue.\n?first($x).\nsecond($x) <- (q($x) -> true ; none($x)).\n?second($x).\nthird($x) <- (q($x) -> true).\n?third($x).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![vec!["x = a"], vec!["x = z"]]
+        );
+    }
+
+    #[test]
+    fn equality_test() {
+        let mut env = Environment::new();
+        let program = "p(f($x), $x).\n?p($y, a).\n?f($z) = f(g($w)).\n?f(a) \\= f($v).\n?f(a) \\= g($v).\n?$x == $x.\n?$x \\== $y.\n?$x == $y.";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![
+                vec!["y = f(a)"],
+                vec!["w = $w", "z = g($w)"],
+                vec!["v = $v"],
+                vec!["x = $x"],
+                vec!["x = $x", "y = $y"]
+            ]
+        );
+    }
+
+    #[test]
+    fn dif_test() {
+        let mut env = Environment::new();
+        let program = "p(a).\np(b).\nq($x) <- dif($x, a), p($x).\n?q($y).\n?dif($x, $x).\n?dif(f($x), g($y)).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![vec!["y = b"], vec!["x = $x", "y = $y"]]
+        );
+    }
+
+    #[test]
+    fn assert_retract_test() {
+        let mut env = Environment::new();
+        let program = "counter(z).\nbump <- retract(counter($n)), assertz(counter(s($n))).\n?bump.\n?counter($c).";
+        assert_eq!(answers(&mut env, program), vec![vec![], vec!["c = s(z)"]]);
+
+        // Clauses added while a goal is running are not seen by the goal.
+        let program = "item(a).\ncopy <- item($x), assertz(item(c)).\n?copy.\n?item($y).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![vec![], vec!["y = a"], vec!["y = c"]]
+        );
+    }
+
+    #[test]
+    fn all_solutions_test() {
+        let mut env = Environment::new();
+        let program = "age(bob, 30).\nage(amy, 25).\nage(tom, 30).\n\
+            ?findall($n, age($n, $a), $l).\n\
+            ?findall($n, age($n, 99), $l).\n\
+            ?bagof($n, age($n, $a), $l).\n\
+            ?setof($a, age($n, $a), $l).\n\
+            ?bagof($n, age($n, 99), $l).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![
+                vec!["a = $a", "l = [bob, amy, tom]", "n = $n"],
+                vec!["l = []", "n = $n"],
+                vec!["a = 25", "l = [amy]", "n = $n"],
+                vec!["a = 30", "l = [bob, tom]", "n = $n"],
+                vec!["a = $a", "l = [25]", "n = amy"],
+                vec!["a = $a", "l = [30]", "n = bob"],
+                vec!["a = $a", "l = [30]", "n = tom"],
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregate_all_test() {
+        let mut env = Environment::new();
+        let program = "cost(a, 3).\ncost(b, 5).\ncost(c, 3).\n\
+            ?aggregate_all(count, cost($x, $y), $r).\n\
+            ?aggregate_all(sum($y), cost($x, $y), $r).\n\
+            ?aggregate_all(max($y), cost($x, $y), $r).\n\
+            ?aggregate_all(set($y), cost($x, $y), $r).\n\
+            ?aggregate_all(min($y), cost(d, $y), $r).";
+        let answers = answers(&mut env, program);
+        let results = answers
+            .iter()
+            .map(|answer| answer[0].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(results, vec!["r = 3", "r = 11", "r = 5", "r = [3, 5]"]);
+    }
+
+    #[test]
+    fn call_test() {
+        let mut env = Environment::new();
+        load_prelude(&mut env).unwrap();
+        let program = "succ($x, s($x)).\neven(z).\neven(s(s($x))) <- even($x).\n\
+            ?call(add(s(z)), z, $r).\n\
+            ?maplist(succ, [z, s(z)], $l).\n\
+            ?foldl(add, [s(z), s(s(z))], z, $n).\n\
+            ?include(even, [z, s(z), s(s(z))], $l).\n\
+            ?exclude(even, [z, s(z), s(s(z))], $l).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![
+                vec!["r = s(z)"],
+                vec!["l = [s(z), s(s(z))]"],
+                vec!["n = s(s(s(z)))"],
+                vec!["l = [z, s(s(z))]"],
+                vec!["l = [s(z)]"],
+            ]
+        );
+    }
+
+    #[test]
+    fn module_test() {
+        let mut env = Environment::new();
+        let program = "#module a.\n#export double/2.\n\
+            helper($x, s(s($x))).\n\
+            double(z, z).\ndouble(s($x), $y) <- double($x, $z), helper($z, $y).\n\
+            #module b.\n#export wrap/2.\n#import a.\n\
+            helper($x, w($x)).\nwrap($x, $y) <- double($x, $z), helper($z, $y).";
+        answers(&mut env, program);
+
+        // Each module sees its own helper/2.
+        let program = "?a:double(s(z), $x).\n?b:wrap(s(z), $y).\n?double(z, $x).\n\
+            #import a.\n?double(z, $x).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![vec!["x = s(s(z))"], vec!["y = w(s(s(z)))"], vec!["x = z"]]
+        );
+
+        let (_, statements) =
+            parse_program(LocatedSpan::new("?a:helper(z, $x)."), Syntax::Terminated).unwrap();
+        let Some(Statement::Query(mut stmt)) = statements.into_iter().next() else {
+            panic!("expected a query");
+        };
+        assert!(matches!(
+            env.qualify(USER_MODULE, &mut stmt.query),
+            Err(ErrorKind::PrivatePredicate(id)) if id.name == "a:helper"
+        ));
+
+        // A call written before the module is loaded is checked when it is made.
+        let mut env = Environment::new();
+        let program = "#module b.\n#export go/1.\ngo($x) <- c:helper($x).\n\
+            #module c.\n#export pub/1.\nhelper(secret).\npub($x) <- helper($x).";
+        answers(&mut env, program);
+        assert_eq!(answers(&mut env, "?c:pub($x)."), vec![vec!["x = secret"]]);
+        let (_, statements) =
+            parse_program(LocatedSpan::new("?b:go($x)."), Syntax::Terminated).unwrap();
+        let Some(Statement::Query(mut stmt)) = statements.into_iter().next() else {
+            panic!("expected a query");
+        };
+        let (mut solution_gen, _) = SolutionGenerator::new(&mut stmt.query, &mut env).unwrap();
+        assert!(matches!(
+            solution_gen.next(),
+            Err(ErrorKind::PrivatePredicate(id)) if id.name == "c:helper"
+        ));
+    }
+
+    #[test]
+    fn module_meta_test() {
+        let mut env = Environment::new();
+        load_prelude(&mut env).unwrap();
+        let program = "#module a.\n#export pub/1, all/1.\n\
+            hh(q).\n\
+            pub($x) <- maplist(hh, [$x]).\n\
+            all($l) <- findall($x, call(hh, $x), $l).";
+        answers(&mut env, program);
+        let program = "?a:pub($x).\n?a:all($l).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![vec!["x = q"], vec!["l = [q]"]]
+        );
+        // The closures are written back without the module.
+        assert!(env.dump().contains("pub($x) <- maplist(hh, [$x]).\n"));
+
+        // A user predicate named like a meta-predicate of the prelude takes its arguments as they are.
+        let program = "#module cfg.\n#export include/3.\n\
+            include(debug, $x, $x).";
+        answers(&mut env, program);
+        let program = "?cfg:include(debug, a, $y).";
+        assert_eq!(answers(&mut env, program), vec![vec!["y = a"]]);
+        assert!(env.dump().contains("include(debug, $x, $x).\n"));
+    }
+
+    #[test]
+    fn catch_test() {
+        let mut env = Environment::new();
+        let program = "p(1).\np(2).\ntrue.\n\
+            check($x) <- ($x = 2 -> throw(bad($x)) ; true).\n\
+            ?catch(check(2), bad($y), true).\n\
+            ?catch(check(1), bad($y), true).\n\
+            ?catch(catch(check(2), other, true), bad($y), true).\n\
+            ?catch(aggregate_all(foo, p($x), $r), error($e, context($p, $m)), true).";
+        assert_eq!(
+            answers(&mut env, program),
+            vec![
+                vec!["y = 2"],
+                vec!["y = $y"],
+                vec!["y = 2"],
+                vec![
+                    "e = type_error(aggregation, foo)",
+                    "m = \"\\\"aggregate_all/3\\\" expects aggregation, but got \\\"foo\\\".\"",
+                    "p = \"/\"(aggregate_all, 3)",
+                    "r = $r",
+                    "x = $x"
+                ],
+            ]
+        );
+
+        // The bindings made by the goal are undone, and its other alternatives are abandoned.
+        let program = "pc($x) <- p($x), check($x).\n?catch(pc($x), bad($y), true).";
+        assert_eq!(answers(&mut env, program), vec![vec!["x = $x", "y = 2"]]);
+
+        let program = "?catch(check(2), other, true).";
+        let (_, statements) = parse_program(LocatedSpan::new(program), Syntax::Terminated).unwrap();
+        let Some(Statement::Query(mut stmt)) = statements.into_iter().next() else {
+            panic!("expected a query");
+        };
+        let (mut solution_gen, _) = SolutionGenerator::new(&mut stmt.query, &mut env).unwrap();
+        assert!(matches!(solution_gen.next(), Err(ErrorKind::Thrown(_))));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fmt, io};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    ast::{Expr, PredicateObj, VarExpr},
+    builtins::atom_to_int,
+    env::Environment,
+    error::{Error, ErrorKind, WarningKind},
+    evaluation::SolutionGenerator,
+    json::STRING_TAG,
+};
+
+///
+/// A binding in a relation, which is an integer or the text of any other atom.
+/// A string tagged as `str(Atom)` is text even if it is written like an integer.
+///
+#[derive(Debug, PartialEq)]
+pub enum Cell {
+    Int(i64),
+    Text(String),
+}
+
+impl Cell {
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        let Expr::Atom(atom) = expr else {
+            return None;
+        };
+        match &atom.arguments[..] {
+            [] => Some(match atom_to_int(&atom.name) {
+                Some(value) => Cell::Int(value),
+                None => Cell::Text(atom.name.to_string()),
+            }),
+            [Expr::Atom(text)] if atom.name == STRING_TAG && text.arguments.is_empty() => {
+                Some(Cell::Text(text.name.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cell::Int(value) => write!(f, "{}", value),
+            Cell::Text(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+///
+/// The bindings of some variables in all the answers of a query, where every binding is an atom.
+///
+#[derive(Debug, PartialEq)]
+pub struct Relation {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Relation {
+    ///
+    /// Writes the relation as CSV with the names of the variables in the first row.
+    ///
+    pub fn write_csv(&self, writer: impl io::Write) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(&self.columns)?;
+        for row in &self.rows {
+            writer.write_record(row.iter().map(Cell::to_string))?;
+        }
+        writer.flush()
+    }
+
+    ///
+    /// Encodes the relation as an array of objects keyed by the names of the variables,
+    /// where integers are numbers and other atoms, including the tagged strings, are strings.
+    ///
+    pub fn to_json(&self) -> Value {
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| {
+                let object = self
+                    .columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, value)| {
+                        let value = match value {
+                            Cell::Int(value) => json!(value),
+                            Cell::Text(text) => json!(text),
+                        };
+                        (column.to_string(), value)
+                    })
+                    .collect::<Map<_, _>>();
+                Value::Object(object)
+            })
+            .collect();
+        Value::Array(rows)
+    }
+}
+
+///
+/// Runs the query to exhaustion and collects the bindings of the variables named in `columns`,
+/// or of all the variables in the query sorted by their names if `columns` is empty.
+/// Fails if a variable is left unbound or bound to a compound term other than a tagged string in any answer.
+/// The warnings reported while running the query are returned with the relation.
+///
+pub fn query_relation(
+    env: &mut Environment,
+    query: &mut PredicateObj,
+    columns: &[String],
+) -> Result<(Relation, Vec<WarningKind>), Error> {
+    let (mut solution_gen, mut name_table) = SolutionGenerator::new(query, env)?;
+    name_table.sort();
+    let columns = if columns.is_empty() {
+        name_table.iter().map(|(name, _)| name.clone()).collect()
+    } else {
+        columns.to_vec()
+    };
+    let ids = columns
+        .iter()
+        .map(|column| {
+            name_table
+                .iter()
+                .find(|(name, _)| name == column)
+                .map(|(_, id)| *id)
+        })
+        .collect::<Vec<_>>();
+
+    let mut rows = Vec::new();
+    while let Some(solution) = solution_gen.next()? {
+        let row = columns
+            .iter()
+            .zip(&ids)
+            .map(|(column, id)| match id.and_then(|id| solution.get(id)) {
+                Some(expr) => Cell::from_expr(expr)
+                    .ok_or_else(|| ErrorKind::NotExportable(column.clone(), expr.clone())),
+                // A variable which does not appear in the query is never bound.
+                None => Err(ErrorKind::NotExportable(
+                    column.clone(),
+                    VarExpr::new(column.clone()),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.push(row);
+    }
+    let warnings = solution_gen.take_warnings();
+    Ok((Relation { columns, rows }, warnings))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ast::Statement, env::run_program};
+
+    fn run(program: &str, columns: &[&str]) -> Result<Relation, Error> {
+        let mut env = Environment::new();
+        let mut query = None;
+        run_program(&mut env, program, |env, module, stmt| {
+            if let Statement::Query(mut stmt) = stmt {
+                env.qualify(module, &mut stmt.query).unwrap();
+                query = Some(stmt.query);
+            }
+        });
+        let columns = columns.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let (relation, _) = query_relation(&mut env, &mut query.unwrap(), &columns)?;
+        Ok(relation)
+    }
+
+    #[test]
+    fn query_relation_test() {
+        let program = "city(Tokyo, 13960000).\ncity(\"New York\", 8336817).\n";
+        let relation = run(&format!("{}?city($n, $p).", program), &[]).unwrap();
+        assert_eq!(relation.columns, vec!["n", "p"]);
+
+        let mut csv = Vec::new();
+        relation.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "n,p\nTokyo,13960000\nNew York,8336817\n"
+        );
+        assert_eq!(
+            relation.to_json(),
+            json!([
+                { "n": "Tokyo", "p": 13960000 },
+                { "n": "New York", "p": 8336817 },
+            ])
+        );
+
+        let relation = run(&format!("{}?city($n, $p).", program), &["p"]).unwrap();
+        assert_eq!(
+            relation.rows,
+            vec![vec![Cell::Int(13960000)], vec![Cell::Int(8336817)]]
+        );
+
+        // A tagged string and an atom written unlike an integer stay strings.
+        let relation = run("code(42).\ncode(str(42)).\ncode(007).\n?code($c).", &[]).unwrap();
+        assert_eq!(
+            relation.to_json(),
+            json!([{ "c": 42 }, { "c": "42" }, { "c": "007" }])
+        );
+
+        let err = run("p(s(z)).\n?p($x).", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[E0017] The variable \"$x\" is bound to \"s(z)\", which is not an atom."
+        );
+        let err = run("p($y).\n?p($x).", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[E0017] The variable \"$x\" is left unbound in an answer."
+        );
+    }
+}
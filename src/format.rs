@@ -0,0 +1,378 @@
+use nom_locate::LocatedSpan;
+
+use crate::{
+    ast::{DefStatement, Expr, Goal, PredicateObj, Statement, INFIX_OPERATORS},
+    error::Error,
+    parser::{parse_next_statement, parse_program_recovering, skip_blank, Syntax},
+};
+
+/// The line width which the formatter tries to keep within by default.
+pub const DEFAULT_WIDTH: usize = 80;
+
+const INDENT: usize = 4;
+
+///
+/// A document which can be laid out flat or broken into lines.
+///
+enum Doc {
+    Text(String),
+    /// A space, or a new line if the enclosing group is broken.
+    Line,
+    /// Nothing, or a new line if the enclosing group is broken.
+    SoftLine,
+    Concat(Vec<Doc>),
+    Nest(Box<Doc>),
+    /// Laid out flat if it fits in the rest of the line, and broken otherwise.
+    Group(Box<Doc>),
+}
+
+fn text(s: &str) -> Doc {
+    Doc::Text(s.to_string())
+}
+
+fn nest(doc: Doc) -> Doc {
+    Doc::Nest(Box::new(doc))
+}
+
+fn group(doc: Doc) -> Doc {
+    Doc::Group(Box::new(doc))
+}
+
+///
+/// Joins the documents with `separator` followed by a line break.
+///
+fn join(docs: Vec<Doc>, separator: &str) -> Doc {
+    let mut joined = Vec::new();
+    for (idx, doc) in docs.into_iter().enumerate() {
+        if idx > 0 {
+            joined.push(text(separator));
+            joined.push(Doc::Line);
+        }
+        joined.push(doc);
+    }
+    Doc::Concat(joined)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+///
+/// Checks whether the documents fit in `rest` columns up to the next line break.
+///
+fn fits(mut rest: isize, mut cmds: Vec<(usize, Mode, &Doc)>) -> bool {
+    while let Some((indent, mode, doc)) = cmds.pop() {
+        if rest < 0 {
+            return false;
+        }
+        match doc {
+            Doc::Text(s) => rest -= s.chars().count() as isize,
+            Doc::Line | Doc::SoftLine if mode == Mode::Break => return true,
+            Doc::Line => rest -= 1,
+            Doc::SoftLine => {}
+            Doc::Concat(docs) => {
+                cmds.extend(docs.iter().rev().map(|doc| (indent, mode, doc)));
+            }
+            Doc::Nest(doc) => cmds.push((indent + INDENT, mode, doc)),
+            Doc::Group(doc) => cmds.push((indent, mode, doc)),
+        }
+    }
+    rest >= 0
+}
+
+fn render(doc: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    let mut cmds = vec![(0, Mode::Break, doc)];
+    while let Some((indent, mode, doc)) = cmds.pop() {
+        match doc {
+            Doc::Text(s) => {
+                out.push_str(s);
+                column += s.chars().count();
+            }
+            Doc::Line | Doc::SoftLine if mode == Mode::Break => {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                column = indent;
+            }
+            Doc::Line => {
+                out.push(' ');
+                column += 1;
+            }
+            Doc::SoftLine => {}
+            Doc::Concat(docs) => {
+                cmds.extend(docs.iter().rev().map(|doc| (indent, mode, doc)));
+            }
+            Doc::Nest(doc) => cmds.push((indent + INDENT, mode, doc)),
+            Doc::Group(doc) => {
+                let mut rest = cmds.clone();
+                rest.push((indent, Mode::Flat, doc));
+                let rest_width = width as isize - column as isize;
+                if fits(rest_width, rest) {
+                    cmds.push((indent, Mode::Flat, doc));
+                } else {
+                    cmds.push((indent, Mode::Break, doc));
+                }
+            }
+        }
+    }
+    out
+}
+
+///
+/// Lays out `name(args...)`, which breaks after `(` if it does not fit.
+///
+fn compound_doc(name: &str, args: &[Expr]) -> Doc {
+    if args.is_empty() {
+        return text(name);
+    }
+    group(Doc::Concat(vec![
+        text(name),
+        text("("),
+        nest(Doc::Concat(vec![
+            Doc::SoftLine,
+            join(args.iter().map(expr_doc).collect(), ","),
+        ])),
+        Doc::SoftLine,
+        text(")"),
+    ]))
+}
+
+fn expr_doc(expr: &Expr) -> Doc {
+    if let Some((items, tail)) = expr.as_list() {
+        let mut inner = vec![
+            Doc::SoftLine,
+            join(items.into_iter().map(expr_doc).collect(), ","),
+        ];
+        if !tail.is_nil() {
+            inner.push(Doc::Line);
+            inner.push(text("| "));
+            inner.push(expr_doc(tail));
+        }
+        return group(Doc::Concat(vec![
+            text("["),
+            nest(Doc::Concat(inner)),
+            Doc::SoftLine,
+            text("]"),
+        ]));
+    }
+    match expr {
+        Expr::Atom(atom) => compound_doc(&atom.written_name(), &atom.arguments),
+        Expr::Var(_) => text(&expr.to_string()),
+    }
+}
+
+fn predicate_doc(pred: &PredicateObj) -> Doc {
+    if pred.arguments.len() == 2 && INFIX_OPERATORS.contains(&pred.name.as_str()) {
+        Doc::Concat(vec![
+            expr_doc(&pred.arguments[0]),
+            text(&format!(" {} ", pred.name)),
+            expr_doc(&pred.arguments[1]),
+        ])
+    } else {
+        compound_doc(&pred.name, &pred.arguments)
+    }
+}
+
+///
+/// Lays out the goals separated by `,`. A nested conjunction keeps its parentheses.
+///
+fn conjunction_doc(goals: &[Goal]) -> Doc {
+    let docs = goals
+        .iter()
+        .map(|goal| match goal {
+            Goal::And(_) => parenthesized(goal_doc(goal)),
+            _ => goal_doc(goal),
+        })
+        .collect();
+    join(docs, ",")
+}
+
+fn parenthesized(doc: Doc) -> Doc {
+    group(Doc::Concat(vec![
+        text("("),
+        nest(Doc::Concat(vec![Doc::SoftLine, doc])),
+        Doc::SoftLine,
+        text(")"),
+    ]))
+}
+
+fn goal_doc(goal: &Goal) -> Doc {
+    match goal {
+        Goal::Pred(pred) => predicate_doc(pred),
+        Goal::And(goals) => conjunction_doc(goals),
+        Goal::Or(_) | Goal::IfThenElse(_, _, _) => parenthesized(unparenthesized_doc(goal)),
+    }
+}
+
+///
+/// Lays out a disjunction or an if-then-else without the surrounding parentheses,
+/// putting each alternative on its own line if broken.
+///
+fn unparenthesized_doc(goal: &Goal) -> Doc {
+    match goal {
+        Goal::Or(goals) => {
+            let mut docs = Vec::new();
+            for (idx, goal) in goals.iter().enumerate() {
+                if idx > 0 {
+                    docs.push(Doc::Line);
+                    docs.push(text("; "));
+                }
+                docs.push(goal_doc(goal));
+            }
+            Doc::Concat(docs)
+        }
+        Goal::IfThenElse(cond, then, otherwise) => {
+            let mut docs = vec![goal_doc(cond), text(" -> "), goal_doc(then)];
+            if let Some(otherwise) = otherwise {
+                docs.push(Doc::Line);
+                docs.push(text("; "));
+                docs.push(unparenthesized_doc(otherwise));
+            }
+            Doc::Concat(docs)
+        }
+        _ => goal_doc(goal),
+    }
+}
+
+fn def_doc(stmt: &DefStatement) -> Doc {
+    if stmt.premises.is_empty() {
+        return predicate_doc(&stmt.conclusion);
+    }
+    group(Doc::Concat(vec![
+        predicate_doc(&stmt.conclusion),
+        text(" <-"),
+        nest(Doc::Concat(vec![
+            Doc::Line,
+            conjunction_doc(&stmt.premises),
+        ])),
+    ]))
+}
+
+///
+/// Prints a statement in the canonical layout, wrapping lines longer than `width`.
+/// The compatible syntax is kept on a single line.
+///
+pub fn format_statement(stmt: &Statement, syntax: Syntax, width: usize) -> String {
+    let doc = match stmt {
+        Statement::Def(stmt) => def_doc(stmt),
+        Statement::Query(stmt) => Doc::Concat(vec![text("?"), predicate_doc(&stmt.query)]),
+        Statement::Directive(directive) => text(&directive.to_string()),
+        Statement::Test(test) => text(&test.to_string()),
+    };
+    match syntax {
+        Syntax::Terminated => render(&Doc::Concat(vec![doc, text(".")]), width),
+        Syntax::Compat => render(&doc, usize::MAX / 2),
+    }
+}
+
+///
+/// Formats a whole program while keeping its comments.
+/// Blank lines between statements are kept, but consecutive ones are merged.
+/// A statement with comments inside is left as it is.
+/// Returns the syntax errors if the program is malformed.
+///
+pub fn format_program(program: &str, syntax: Syntax, width: usize) -> Result<String, Vec<Error>> {
+    let (_, errors) = parse_program_recovering(LocatedSpan::new(program), syntax);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut text = LocatedSpan::new(program);
+    loop {
+        let rest = skip_blank(text);
+        let gap = &text.fragment()[..rest.location_offset() - text.location_offset()];
+
+        let mut gap_lines = gap.split('\n').map(str::trim).collect::<Vec<_>>();
+        // The first line of the gap continues the previous statement,
+        // and the last one is the indentation of the next statement.
+        if text.location_offset() > 0 {
+            let trailing = gap_lines.remove(0);
+            if let Some(last) = lines.last_mut().filter(|_| !trailing.is_empty()) {
+                last.push(' ');
+                last.push_str(trailing);
+            }
+        }
+        let leading = gap_lines.pop().unwrap_or_default();
+        for line in gap_lines {
+            if !line.is_empty() {
+                lines.push(line.to_string());
+            } else if lines.last().is_some_and(|last| !last.is_empty()) {
+                lines.push(String::new());
+            }
+        }
+        if !leading.is_empty() {
+            lines.push(leading.to_string());
+        }
+
+        if rest.is_empty() {
+            break;
+        }
+        let (after, stmt) = parse_next_statement(rest, syntax).map_err(|err| vec![err.into()])?;
+        let source = &rest.fragment()[..after.location_offset() - rest.location_offset()];
+        if source.contains('%') {
+            lines.extend(source.lines().map(|line| line.trim_end().to_string()));
+        } else {
+            lines.extend(
+                format_statement(&stmt, syntax, width)
+                    .lines()
+                    .map(|line| line.to_string()),
+            );
+        }
+        text = after;
+    }
+
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    let mut formatted = lines.join("\n");
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse_program;
+
+    #[test]
+    fn format_statement_test() {
+        let program = "add(s($x),$y,s($z))<-add($x,$y,$z).\n\
+            sign($x, $s) <- (neg($x) -> $s = m ; zero($x) -> $s = z ; $s = p).\n\
+            ?member($x,[a,b|$t]).";
+        let (_, items) = parse_program(LocatedSpan::new(program), Syntax::Terminated).unwrap();
+        let formatted = items
+            .iter()
+            .map(|item| format_statement(item, Syntax::Terminated, 40))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            formatted,
+            vec![
+                "add(s($x), $y, s($z)) <-\n    add($x, $y, $z).",
+                "sign($x, $s) <-\n    (\n        neg($x) -> $s = m\n        ; zero($x) -> $s = z\n        ; $s = p\n    ).",
+                "?member($x, [a, b | $t]).",
+            ]
+        );
+
+        // The formatted statements are parsed into the same ones.
+        let reformatted = formatted.join("\n");
+        let (_, reparsed) =
+            parse_program(LocatedSpan::new(&reformatted), Syntax::Terminated).unwrap();
+        assert_eq!(reparsed, items);
+    }
+
+    #[test]
+    fn format_program_test() {
+        let program = "% Numbers.\n\n\n  num(z).   % zero\nnum(s($n)) <- % successor\n    num($n).\n\n?num(   z).\n";
+        assert_eq!(
+            format_program(program, Syntax::Terminated, DEFAULT_WIDTH).unwrap(),
+            "% Numbers.\n\nnum(z). % zero\nnum(s($n)) <- % successor\n    num($n).\n\n?num(z).\n"
+        );
+        assert!(format_program("num(z", Syntax::Terminated, DEFAULT_WIDTH).is_err());
+    }
+}
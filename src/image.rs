@@ -0,0 +1,336 @@
+use std::{collections::HashMap, io};
+
+use crate::{
+    ast::{AtomExpr, Expr, Goal, PredicateId, PredicateObj, VarExpr},
+    error::ErrorKind,
+};
+
+/// The first bytes of an image, which tell it from other files.
+pub const IMAGE_MAGIC: &[u8; 8] = b"LONLYIMG";
+/// The version of the image format. It is bumped whenever the layout changes.
+pub const IMAGE_VERSION: u32 = 1;
+
+///
+/// Encodes terms into an image, which is laid out as
+/// the magic, the version, the table of the names and then the body.
+/// Every name is stored once in the table and referred to by its index.
+/// Integers are written in LEB128 so that small ones take a single byte.
+///
+#[derive(Default)]
+pub struct ImageWriter {
+    names: Vec<String>,
+    indices: HashMap<String, u32>,
+    body: Vec<u8>,
+}
+
+impl ImageWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        write_u32(&mut self.body, value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.body.push(value as u8);
+    }
+
+    pub fn write_str(&mut self, name: &str) {
+        let idx = match self.indices.get(name) {
+            Some(idx) => *idx,
+            None => {
+                let idx = self.names.len() as u32;
+                self.names.push(name.to_string());
+                self.indices.insert(name.to_string(), idx);
+                idx
+            }
+        };
+        self.write_u32(idx);
+    }
+
+    pub fn write_len(&mut self, len: usize) {
+        self.write_u32(len as u32);
+    }
+
+    pub fn write_id(&mut self, id: &PredicateId) {
+        self.write_str(&id.name);
+        self.write_len(id.arity);
+    }
+
+    pub fn write_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Atom(atom) => {
+                self.body.push(0);
+                self.write_str(&atom.name);
+                self.write_len(atom.arguments.len());
+                for arg in atom.arguments.iter() {
+                    self.write_expr(arg);
+                }
+            }
+            Expr::Var(var) => match var.id {
+                None => {
+                    self.body.push(1);
+                    self.write_str(&var.name);
+                }
+                Some(id) => {
+                    self.body.push(2);
+                    self.write_str(&var.name);
+                    self.write_u32(id);
+                }
+            },
+        }
+    }
+
+    pub fn write_predicate(&mut self, pred_obj: &PredicateObj) {
+        self.write_str(&pred_obj.name);
+        self.write_len(pred_obj.arguments.len());
+        for arg in &pred_obj.arguments {
+            self.write_expr(arg);
+        }
+    }
+
+    pub fn write_goal(&mut self, goal: &Goal) {
+        match goal {
+            Goal::Pred(pred_obj) => {
+                self.body.push(0);
+                self.write_predicate(pred_obj);
+            }
+            Goal::And(goals) | Goal::Or(goals) => {
+                self.body
+                    .push(if matches!(goal, Goal::And(_)) { 1 } else { 2 });
+                self.write_len(goals.len());
+                for goal in goals {
+                    self.write_goal(goal);
+                }
+            }
+            Goal::IfThenElse(cond, then, otherwise) => {
+                self.body.push(3);
+                self.write_goal(cond);
+                self.write_goal(then);
+                self.write_bool(otherwise.is_some());
+                if let Some(otherwise) = otherwise {
+                    self.write_goal(otherwise);
+                }
+            }
+        }
+    }
+
+    pub fn finish(self, mut writer: impl io::Write) -> io::Result<()> {
+        let mut header = IMAGE_MAGIC.to_vec();
+        header.extend(IMAGE_VERSION.to_le_bytes());
+        write_u32(&mut header, self.names.len() as u32);
+        for name in &self.names {
+            write_u32(&mut header, name.len() as u32);
+            header.extend(name.as_bytes());
+        }
+        writer.write_all(&header)?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn write_u32(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+///
+/// Decodes the terms written by `ImageWriter`.
+/// A truncated or corrupted image is reported as `ErrorKind::InvalidData`.
+///
+pub struct ImageReader<'a> {
+    names: Vec<String>,
+    bytes: &'a [u8],
+}
+
+impl<'a> ImageReader<'a> {
+    ///
+    /// Reads the header and the table of the names,
+    /// and checks that the image is written in the version of the format which this build reads.
+    ///
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ErrorKind> {
+        let Some(bytes) = bytes.strip_prefix(IMAGE_MAGIC) else {
+            return Err(invalid());
+        };
+        let (version, bytes) = bytes.split_first_chunk::<4>().ok_or_else(invalid)?;
+        let version = u32::from_le_bytes(*version);
+        if version != IMAGE_VERSION {
+            return Err(ErrorKind::IncompatibleImage(version));
+        }
+
+        let mut reader = ImageReader {
+            names: Vec::new(),
+            bytes,
+        };
+        let len = reader.read_len()?;
+        for _ in 0..len {
+            let len = reader.read_len()?;
+            let name = reader.take(len)?;
+            let name = String::from_utf8(name.to_vec()).map_err(|_| invalid())?;
+            reader.names.push(name);
+        }
+        Ok(reader)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ErrorKind> {
+        if self.bytes.len() < len {
+            return Err(invalid());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, ErrorKind> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ErrorKind> {
+        let mut value = 0u32;
+        for shift in (0..32).step_by(7) {
+            let byte = self.read_byte()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte < 0x80 {
+                return Ok(value);
+            }
+        }
+        Err(invalid())
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, ErrorKind> {
+        match self.read_byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn read_len(&mut self) -> Result<usize, ErrorKind> {
+        let len = self.read_u32()? as usize;
+        // Every item takes at least a byte, which rejects absurd lengths before allocating.
+        if len > self.bytes.len() {
+            return Err(invalid());
+        }
+        Ok(len)
+    }
+
+    pub fn read_str(&mut self) -> Result<String, ErrorKind> {
+        let idx = self.read_u32()? as usize;
+        self.names.get(idx).cloned().ok_or_else(invalid)
+    }
+
+    pub fn read_id(&mut self) -> Result<PredicateId, ErrorKind> {
+        let name = self.read_str()?;
+        let arity = self.read_u32()? as usize;
+        Ok(PredicateId::new(name, arity))
+    }
+
+    pub fn read_expr(&mut self) -> Result<Expr, ErrorKind> {
+        match self.read_byte()? {
+            0 => {
+                let name = self.read_str()?;
+                let len = self.read_len()?;
+                let arguments = (0..len)
+                    .map(|_| self.read_expr())
+                    .collect::<Result<_, _>>()?;
+                Ok(AtomExpr::new(name, arguments))
+            }
+            1 => Ok(VarExpr::new(self.read_str()?)),
+            2 => {
+                let name = self.read_str()?;
+                let id = self.read_u32()?;
+                Ok(Expr::Var(VarExpr {
+                    name: name.into(),
+                    id: Some(id),
+                }))
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn read_predicate(&mut self) -> Result<PredicateObj, ErrorKind> {
+        let name = self.read_str()?;
+        let len = self.read_len()?;
+        let arguments = (0..len)
+            .map(|_| self.read_expr())
+            .collect::<Result<_, _>>()?;
+        Ok(PredicateObj::new(name, arguments))
+    }
+
+    pub fn read_goal(&mut self) -> Result<Goal, ErrorKind> {
+        match self.read_byte()? {
+            0 => Ok(Goal::Pred(self.read_predicate()?)),
+            tag @ (1 | 2) => {
+                let len = self.read_len()?;
+                let goals = (0..len)
+                    .map(|_| self.read_goal())
+                    .collect::<Result<_, _>>()?;
+                Ok(if tag == 1 {
+                    Goal::And(goals)
+                } else {
+                    Goal::Or(goals)
+                })
+            }
+            3 => {
+                let cond = self.read_goal()?;
+                let then = self.read_goal()?;
+                let otherwise = if self.read_bool()? {
+                    Some(Box::new(self.read_goal()?))
+                } else {
+                    None
+                };
+                Ok(Goal::IfThenElse(Box::new(cond), Box::new(then), otherwise))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn invalid() -> ErrorKind {
+    ErrorKind::InvalidData("image".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_header_test() {
+        let mut writer = ImageWriter::new();
+        writer.write_expr(&AtomExpr::new(
+            "s".to_string(),
+            vec![VarExpr::new("x".to_string())],
+        ));
+        writer.write_u32(300);
+        let mut bytes = Vec::new();
+        writer.finish(&mut bytes).unwrap();
+
+        let mut reader = ImageReader::new(&bytes).unwrap();
+        assert_eq!(reader.read_expr().unwrap().to_string(), "s($x)");
+        assert_eq!(reader.read_u32().unwrap(), 300);
+        assert!(reader.is_empty());
+
+        // A truncated image is malformed.
+        let mut reader = ImageReader::new(&bytes[..bytes.len() - 1]).unwrap();
+        reader.read_expr().unwrap();
+        assert_eq!(reader.read_u32().unwrap_err().code(), "E0015");
+
+        bytes[IMAGE_MAGIC.len()] += 1;
+        assert!(matches!(
+            ImageReader::new(&bytes),
+            Err(ErrorKind::IncompatibleImage(2))
+        ));
+        assert_eq!(
+            ImageReader::new(b"% lonly program").err().unwrap().code(),
+            "E0015"
+        );
+    }
+}
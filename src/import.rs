@@ -0,0 +1,173 @@
+use std::io;
+
+use crate::{
+    ast::{AtomExpr, DefStatement, Expr, PredicateId, PredicateObj},
+    env::Environment,
+    error::{Error, ErrorKind, Span},
+    json::json_to_expr,
+};
+
+///
+/// Reads a field of a CSV file as an atom with the same text.
+/// It is an integer if the field is the decimal form of one, while `007` and `+5` stay other atoms.
+///
+pub fn field_to_expr(field: &str) -> Expr {
+    AtomExpr::new(field.to_string(), vec![])
+}
+
+///
+/// Adds a fact of the predicate for every row of the CSV data, and returns the number of the facts.
+/// Every row must have as many fields as the arity. The first row is skipped if it is a header.
+/// Nothing is added if any row is malformed.
+///
+pub fn load_csv(
+    env: &mut Environment,
+    reader: impl io::Read,
+    id: &PredicateId,
+    has_header: bool,
+) -> Result<usize, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(has_header)
+        .flexible(true)
+        .from_reader(reader);
+
+    let mut facts = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|err| {
+            Error::new(ErrorKind::InvalidData("CSV".to_string())).with_source(err)
+        })?;
+        if record.len() != id.arity {
+            let mut err = Error::new(ErrorKind::ArityMismatch(
+                id.name.to_string(),
+                id.arity,
+                record.len(),
+            ));
+            if let Some(position) = record.position() {
+                err = err.with_span(Span {
+                    offset: position.byte() as usize,
+                    line: position.line() as u32,
+                    column: 1,
+                });
+            }
+            return Err(err);
+        }
+        facts.push(record.iter().map(field_to_expr).collect());
+    }
+    add_facts(env, id, facts)
+}
+
+///
+/// Adds a fact of the predicate for every element of the JSON array, or for the whole document
+/// if it is not an array, and returns the number of the facts.
+/// A fact of arity 1 takes the element as it is, while an element must be an array
+/// of as many items as the arity otherwise. Nothing is added if any element is malformed.
+///
+pub fn load_json(env: &mut Environment, text: &str, id: &PredicateId) -> Result<usize, Error> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|err| {
+        let line_start = text
+            .split_inclusive('\n')
+            .take(err.line().saturating_sub(1))
+            .map(str::len)
+            .sum::<usize>();
+        let span = Span {
+            offset: line_start + err.column().saturating_sub(1),
+            line: err.line() as u32,
+            column: err.column(),
+        };
+        Error::new(ErrorKind::InvalidData("JSON".to_string()))
+            .with_span(span)
+            .with_source(err)
+    })?;
+    let rows = match value {
+        serde_json::Value::Array(rows) => rows,
+        value => vec![value],
+    };
+
+    let mut facts = Vec::new();
+    for row in rows {
+        let arguments = match row {
+            row if id.arity == 1 => vec![json_to_expr(&row)],
+            serde_json::Value::Array(items) if items.len() == id.arity => {
+                items.iter().map(json_to_expr).collect()
+            }
+            row => Err(ErrorKind::TypeMismatch(
+                id.clone(),
+                format!("an array of {} items", id.arity),
+                json_to_expr(&row),
+            ))?,
+        };
+        facts.push(arguments);
+    }
+    add_facts(env, id, facts)
+}
+
+fn add_facts(
+    env: &mut Environment,
+    id: &PredicateId,
+    facts: Vec<Vec<Expr>>,
+) -> Result<usize, Error> {
+    let count = facts.len();
+    for arguments in facts {
+        env.update(DefStatement {
+            conclusion: PredicateObj::new(id.name.clone(), arguments),
+            premises: vec![],
+        })?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn facts(env: &Environment, id: &PredicateId) -> Vec<String> {
+        env.get_rules(id)
+            .into_iter()
+            .flatten()
+            .map(|(conclusion, _)| conclusion.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn load_csv_test() {
+        let mut env = Environment::new();
+        let id = PredicateId::new("city".to_string(), 3);
+        let data = "name,country,population\nTokyo,JP,13960000\nNew York,US,8336817\nBond,007,+5\n";
+        assert_eq!(load_csv(&mut env, data.as_bytes(), &id, true).unwrap(), 3);
+        assert_eq!(
+            facts(&env, &id),
+            vec![
+                "city(Tokyo, JP, 13960000)",
+                "city(\"New York\", US, 8336817)",
+                "city(Bond, 007, \"+5\")"
+            ]
+        );
+
+        // A short row is reported with its line, and the other rows are not added.
+        let data = "Paris,FR,2102650\nLondon,GB\n";
+        let err = load_csv(&mut env, data.as_bytes(), &id, false).unwrap_err();
+        assert_eq!(err.code(), "E0002");
+        assert_eq!(err.span().unwrap().line, 2);
+        assert_eq!(facts(&env, &id).len(), 3);
+    }
+
+    #[test]
+    fn load_json_test() {
+        let mut env = Environment::new();
+        let id = PredicateId::new("edge".to_string(), 2);
+        assert_eq!(
+            load_json(&mut env, r#"[["a", "b"], ["b", 1]]"#, &id).unwrap(),
+            2
+        );
+        assert_eq!(facts(&env, &id), vec!["edge(a, b)", "edge(b, 1)"]);
+
+        let err = load_json(&mut env, r#"[["a", "b"], "c"]"#, &id).unwrap_err();
+        assert_eq!(err.code(), "E0009");
+        let err = load_json(&mut env, "[1,", &id).unwrap_err();
+        assert_eq!(err.code(), "E0015");
+
+        let id = PredicateId::new("doc".to_string(), 1);
+        assert_eq!(load_json(&mut env, r#"{"x": [1, 2]}"#, &id).unwrap(), 1);
+        assert_eq!(facts(&env, &id), vec!["doc(obj([kv(x, [1, 2])]))"]);
+    }
+}
@@ -0,0 +1,137 @@
+use serde_json::{json, Map, Value};
+
+use crate::{
+    ast::{AtomExpr, Expr, PredicateObj, VarID},
+    builtins::int_to_expr,
+    env::VarSubstitution,
+};
+
+///
+/// Encodes a term as `{"functor": name, "args": [...]}`, or `{"var": name, "id": id}` for a variable.
+/// Lists are kept as `[|]` cells ending with `[]`, and integers are functors without arguments.
+///
+pub fn expr_to_json(expr: &Expr) -> Value {
+    match expr {
+        Expr::Atom(atom) => json!({
+            "functor": atom.name.as_str(),
+            "args": atom.arguments.iter().map(expr_to_json).collect::<Vec<_>>(),
+        }),
+        Expr::Var(var) => match var.id {
+            Some(id) => json!({ "var": var.name.as_str(), "id": id }),
+            None => json!({ "var": var.name.as_str() }),
+        },
+    }
+}
+
+/// The functor which tags a JSON string written like another value, such as `str(42)` for `"42"`.
+pub const STRING_TAG: &str = "str";
+
+///
+/// Decodes a JSON value into a term. Strings, `true`, `false` and `null` become atoms,
+/// integers become integers and other numbers become atoms such as `1.5`.
+/// A string which would give the same atom as another value is tagged as `str(Atom)`, so `"42"` is not `42`.
+/// Arrays become lists, and objects become `obj([kv(key, value), ...])` sorted by the keys.
+///
+pub fn json_to_expr(value: &Value) -> Expr {
+    let atom = |name: &str| AtomExpr::new(name.to_string(), vec![]);
+    match value {
+        Value::String(s)
+            if serde_json::from_str::<Value>(s)
+                .is_ok_and(|other| !other.is_string() && json_to_expr(&other) == atom(s)) =>
+        {
+            AtomExpr::new(STRING_TAG, vec![atom(s)])
+        }
+        Value::Null => atom("null"),
+        Value::Bool(b) => atom(&b.to_string()),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => int_to_expr(n),
+            None => atom(&n.to_string()),
+        },
+        Value::String(s) => atom(s),
+        Value::Array(items) => Expr::list(items.iter().map(json_to_expr).collect(), Expr::nil()),
+        Value::Object(entries) => {
+            let pairs = entries
+                .iter()
+                .map(|(key, value)| {
+                    AtomExpr::new("kv".to_string(), vec![atom(key), json_to_expr(value)])
+                })
+                .collect();
+            AtomExpr::new("obj".to_string(), vec![Expr::list(pairs, Expr::nil())])
+        }
+    }
+}
+
+///
+/// Encodes an answer as `{"bindings": {name: term, ...}}`, where a variable left unbound is
+/// encoded as itself. The suspended constraints are added as `"constraints": [...]` if any.
+///
+pub fn answer_to_json(
+    name_table: &[(String, VarID)],
+    solution: &VarSubstitution,
+    residual_goals: &[PredicateObj],
+) -> Value {
+    let bindings = name_table
+        .iter()
+        .map(|(name, id)| {
+            let value = match solution.get(*id) {
+                Some(expr) => expr_to_json(expr),
+                None => json!({ "var": name, "id": id }),
+            };
+            (name.to_string(), value)
+        })
+        .collect::<Map<_, _>>();
+
+    let mut answer = json!({ "bindings": bindings });
+    if !residual_goals.is_empty() {
+        let constraints = residual_goals
+            .iter()
+            .map(|goal| goal.to_string())
+            .collect::<Vec<_>>();
+        answer["constraints"] = json!(constraints);
+    }
+    answer
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{AtomExpr, VarExpr};
+
+    #[test]
+    fn expr_to_json_test() {
+        let expr = AtomExpr::new(
+            "s".to_string(),
+            vec![
+                AtomExpr::new("z".to_string(), vec![]),
+                VarExpr::new("x".to_string()),
+            ],
+        );
+        assert_eq!(
+            expr_to_json(&expr),
+            json!({
+                "functor": "s",
+                "args": [
+                    { "functor": "z", "args": [] },
+                    { "var": "x" },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn json_to_expr_test() {
+        let value =
+            json!({ "name": "New York", "pop": 8336817, "area": 783.8, "tags": [true, null] });
+        assert_eq!(
+            json_to_expr(&value).to_string(),
+            "obj([kv(area, \"783.8\"), kv(name, \"New York\"), kv(pop, 8336817), kv(tags, [true, null])])"
+        );
+
+        // Strings are kept apart from the numbers and the constants written the same.
+        let value = json!([42, "42", "007", true, "true", 1.5, "1.5", " 42"]);
+        assert_eq!(
+            json_to_expr(&value).to_string(),
+            "[42, str(42), 007, true, str(true), \"1.5\", str(\"1.5\"), \" 42\"]"
+        );
+    }
+}
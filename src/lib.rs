@@ -0,0 +1,19 @@
+#![allow(clippy::new_ret_no_self)]
+
+pub mod analysis;
+pub mod ast;
+pub mod builtins;
+pub mod env;
+pub mod error;
+pub mod evaluation;
+pub mod export;
+pub mod format;
+pub mod image;
+pub mod import;
+pub mod json;
+pub mod parser;
+pub mod prelude;
+pub mod symbol;
+pub mod testing;
+pub mod unifier;
+pub mod vm;
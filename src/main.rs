@@ -1,94 +1,845 @@
-use crate::ast::Statement;
-use crate::env::Environment;
-use crate::parser::parse_program;
-use error::ErrorKind;
-use evaluation::SolutionGenerator;
+use lonly::ast::{DefStatement, PredicateId, PredicateObj, Statement, VarID, USER_MODULE};
+use lonly::env::{Environment, UnknownMode, VarAllocator};
+use lonly::error::{Error, ErrorKind, WarningKind};
+use lonly::evaluation::{SolutionGenerator, Solver};
+use lonly::export::query_relation;
+use lonly::format::{format_program, format_statement, DEFAULT_WIDTH};
+use lonly::import::{load_csv, load_json};
+use lonly::json::answer_to_json;
+use lonly::parser::{
+    code_chars, parse_program_located, parse_program_recovering, parse_single_def,
+    parse_single_query, Syntax,
+};
+use lonly::prelude::load_prelude;
+use lonly::testing::run_test;
+use lonly::vm::Machine;
 use nom_locate::LocatedSpan;
+use repl::ReplHelper;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use serde_json::json;
+use std::collections::HashMap;
 use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
 
-mod ast;
-mod env;
-mod error;
-mod evaluation;
-mod parser;
-mod unifier;
+mod repl;
 
-fn exec_program(env: &mut Environment, program: &str) -> Result<(), ErrorKind> {
+///
+/// Settings given by the command line options.
+///
+struct Options {
+    syntax: Syntax,
+    prelude: bool,
+    /// Whether the well-formed statements are run even if the program has syntax errors.
+    recover: bool,
+    format: OutputFormat,
+    /// The maximum number of answers printed for each query in JSON.
+    limit: Option<usize>,
+    /// The image loaded instead of the prelude.
+    image: Option<String>,
+    engine: Engine,
+}
+
+///
+/// How the answers of queries are printed.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Answers are shown one by one as `[x = a, y = b]`, waiting for the user.
+    Text,
+    /// All the answers of a query are printed in a single JSON object.
+    Json,
+    /// Each answer is printed in a line as soon as it is found, followed by a summary line.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            _ => Err(()),
+        }
+    }
+}
+
+///
+/// The engine which runs queries.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Engine {
+    /// The tree-walking interpreter, which searches in breadth-first order.
+    Interpreter,
+    /// The bytecode machine of `lonly::vm`, which searches in depth-first order.
+    Vm,
+}
+
+impl FromStr for Engine {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interpreter" => Ok(Engine::Interpreter),
+            "vm" => Ok(Engine::Vm),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A running query and the IDs of its variables.
+type RunningQuery<'a> = (Box<dyn Solver + 'a>, Vec<(String, VarID)>);
+
+///
+/// Starts the query on the engine chosen by the options.
+///
+fn solve<'a>(
+    env: &'a mut Environment,
+    query: &mut PredicateObj,
+    options: &Options,
+) -> Result<RunningQuery<'a>, ErrorKind> {
+    match options.engine {
+        Engine::Interpreter => {
+            let (solution_gen, name_table) = SolutionGenerator::new(query, env)?;
+            Ok((Box::new(solution_gen), name_table))
+        }
+        Engine::Vm => {
+            let (machine, name_table) = Machine::new(query, env)?;
+            Ok((Box::new(machine), name_table))
+        }
+    }
+}
+
+fn exec_program(env: &mut Environment, program: &str, options: &Options) -> Result<(), Error> {
     let program = LocatedSpan::new(program);
-    let (_, statements) = parse_program(program)?;
+    let (statements, errors) = parse_program_recovering(program, options.syntax);
+    if !errors.is_empty() {
+        for err in &errors {
+            report_error(err, options);
+        }
+        if !options.recover {
+            return Ok(());
+        }
+    }
+
+    // `#module` lasts until the end of the program.
+    let mut module = USER_MODULE.to_string();
     for stmt in statements {
         match stmt {
             Statement::Def(stmt) => {
-                env.update(stmt)?;
+                env.define(&module, stmt)?;
             }
+            Statement::Directive(directive) => {
+                env.apply_directive(&mut module, directive)?;
+            }
+            // Tests are run only by `lonly test`.
+            Statement::Test(_) => {}
             Statement::Query(mut stmt) => {
-                let (mut solution_gen, name_tables) = SolutionGenerator::new(&mut stmt.query, env)?;
-                let mut is_interrupted = false;
-                while let Some(solution) = solution_gen.next()? {
-                    let solution = name_tables
-                        .iter()
-                        .map(|(name, id)| {
-                            let expr = solution.get(*id).unwrap();
-                            format!("{} = {}", name, expr.to_string())
-                        })
-                        .collect::<Vec<_>>();
-
-                    print!("[{}]", solution.join(", "));
-                    io::stdout().flush().unwrap();
-
-                    let mut buf = String::new();
-                    let stdin = io::stdin();
-                    stdin.lock().read_line(&mut buf).unwrap();
-                    match buf.as_str() {
-                        "\n" => {}
-                        ".\n" => {
-                            is_interrupted = true;
-                            break
-                        },
-                        _ => Err(ErrorKind::UnknownInstruction)?,
+                env.qualify(&module, &mut stmt.query)?;
+                match options.format {
+                    OutputFormat::Text => answer_query(env, &mut stmt.query, options)?,
+                    OutputFormat::Json | OutputFormat::JsonLines => {
+                        answer_query_json(env, &mut stmt.query, options)?
                     }
                 }
-                if is_interrupted {
-                    println!("Interrupted.");
-                }
-                else {
-                    println!("No answer remains.");
-                }
             }
         }
     }
     Ok(())
 }
 
-fn main() {
-    let mut env = Environment::new();
+///
+/// Prints the answers one by one, waiting for the user to ask for the next one.
+///
+fn answer_query(
+    env: &mut Environment,
+    query: &mut PredicateObj,
+    options: &Options,
+) -> Result<(), Error> {
+    let (mut solution_gen, name_tables) = solve(env, query, options)?;
+
+    // A query without variables is answered by a plain yes or no.
+    if name_tables.is_empty() {
+        let solution = solution_gen.next();
+        for warning in solution_gen.take_warnings() {
+            print_warning(&warning);
+        }
+        if solution?.is_some() {
+            println!("yes");
+        } else {
+            println!("no");
+        }
+        return Ok(());
+    }
+
+    let mut is_interrupted = false;
     loop {
-        print!("> ");
+        let solution = solution_gen.next();
+        for warning in solution_gen.take_warnings() {
+            print_warning(&warning);
+        }
+        let Some(solution) = solution? else {
+            break;
+        };
+
+        // Variables left unbound are omitted.
+        // Suspended constraints are shown after the bindings.
+        let solution = name_tables
+            .iter()
+            .filter_map(|(name, id)| {
+                let expr = solution.get(*id)?;
+                Some(format!("{} = {}", name, expr))
+            })
+            .chain(
+                solution_gen
+                    .residual_goals()
+                    .iter()
+                    .map(|goal| goal.to_string()),
+            )
+            .collect::<Vec<_>>();
+
+        print!("[{}]", solution.join(", "));
         io::stdout().flush().unwrap();
-        let mut line = String::new();
+
+        let mut buf = String::new();
         let stdin = io::stdin();
-        stdin.lock().read_line(&mut line).unwrap();
-        if let Err(err) = exec_program(&mut env, &line) {
-            match err {
-                ErrorKind::ArityMismatch(name, size1, size2) => {
+        stdin.lock().read_line(&mut buf).unwrap();
+        match buf.as_str() {
+            "\n" => {}
+            // EOF also stops enumerating answers.
+            ".\n" | "" => {
+                is_interrupted = true;
+                break;
+            }
+            _ => Err(ErrorKind::UnknownInstruction)?,
+        }
+    }
+    if is_interrupted {
+        println!("Interrupted.");
+    } else {
+        println!("No answer remains.");
+    }
+    Ok(())
+}
+
+///
+/// Prints all the answers without prompts, followed by a summary with the number of the answers
+/// and why the enumeration stopped: `exhausted`, `limit` or `error`.
+/// JSON Lines prints each answer as soon as it is found, while JSON prints a single object at the end.
+/// An error in the query is reported in the summary instead of being returned.
+///
+fn answer_query_json(
+    env: &mut Environment,
+    query: &mut PredicateObj,
+    options: &Options,
+) -> Result<(), Error> {
+    let text = format!("?{}", query);
+    let (mut solution_gen, name_table) = solve(env, query, options)?;
+
+    let mut answers = Vec::new();
+    let mut count = 0;
+    let (termination, error) = loop {
+        if options.limit.is_some_and(|limit| count >= limit) {
+            break ("limit", None);
+        }
+        let solution = solution_gen.next();
+        for warning in solution_gen.take_warnings() {
+            report_warning(&warning, options);
+        }
+        match solution {
+            Ok(Some(solution)) => {
+                count += 1;
+                let answer = answer_to_json(&name_table, &solution, &solution_gen.residual_goals());
+                if options.format == OutputFormat::JsonLines {
+                    let mut record = json!({ "type": "answer" });
+                    record
+                        .as_object_mut()
+                        .unwrap()
+                        .extend(answer.as_object().unwrap().clone());
+                    println!("{}", record);
+                } else {
+                    answers.push(answer);
+                }
+            }
+            Ok(None) => break ("exhausted", None),
+            Err(kind) => {
+                let err = Error::from(kind);
+                let error = json!({ "code": err.code(), "message": err.kind().to_string() });
+                break ("error", Some(error));
+            }
+        }
+    };
+
+    let mut summary = match options.format {
+        OutputFormat::JsonLines => json!({ "type": "summary", "query": text }),
+        _ => json!({ "query": text, "answers": answers }),
+    };
+    summary["count"] = json!(count);
+    summary["termination"] = json!(termination);
+    if let Some(error) = error {
+        summary["error"] = error;
+    }
+    match options.format {
+        OutputFormat::JsonLines => println!("{}", summary),
+        _ => println!("{}", serde_json::to_string_pretty(&summary).unwrap()),
+    }
+    Ok(())
+}
+
+fn exec_command(env: &mut Environment, command: &str, options: &Options) -> Result<(), Error> {
+    let (name, rest) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    match name {
+        "retract" => {
+            let (_, stmt) = parse_single_def(LocatedSpan::new(rest))?;
+            let Statement::Def(mut stmt) = stmt else {
+                unreachable!()
+            };
+
+            let mut var_alloc = VarAllocator::new();
+            let mut id_assignments = HashMap::new();
+            var_alloc.assign_new_ids(&mut stmt.conclusion.arguments, &mut id_assignments)?;
+            for premise in &mut stmt.premises {
+                var_alloc.assign_goal_ids(premise, &mut id_assignments)?;
+            }
+
+            match env.retract(&mut var_alloc, &stmt.conclusion, &stmt.premises)? {
+                Some((stmt, _)) => println!("Retracted: {}", stmt),
+                None => println!("No clause matches."),
+            }
+        }
+        "list" => {
+            // The prelude is listed only on request.
+            let ids = if rest.trim().is_empty() {
+                env.predicate_ids()
+                    .into_iter()
+                    .filter(|id| !env.is_library(id))
+                    .cloned()
+                    .collect()
+            } else {
+                vec![parse_predicate_id(rest)?]
+            };
+            for (idx, id) in ids.iter().enumerate() {
+                if idx > 0 {
+                    println!();
+                }
+                for (conclusion, premises) in env.get_rules(id).into_iter().flatten() {
+                    let stmt = DefStatement {
+                        conclusion: conclusion.clone(),
+                        premises: premises.clone(),
+                    };
                     println!(
-                        "ERR: Arity of the predicate \"{}\" is expected to be {}, but is {}.",
-                        name, size1, size2
+                        "{}",
+                        format_statement(&Statement::Def(stmt), options.syntax, DEFAULT_WIDTH)
                     );
                 }
-                ErrorKind::VariableIDAlreadyAssigned(name) => {
-                    println!("ERR: The id of variable \"{}\" is already assigned.", name);
+            }
+        }
+        "predicates" => {
+            for id in env.predicate_ids() {
+                let num_clauses = env.get_rules(id).map_or(0, |rules| rules.len());
+                let plural = if num_clauses == 1 { "" } else { "s" };
+                let library = if env.is_library(id) { ", library" } else { "" };
+                println!("{} ({} clause{}{})", id, num_clauses, plural, library);
+            }
+        }
+        "clear" => {
+            let id = parse_predicate_id(rest)?;
+            if env.clear(&id) {
+                println!("Cleared: {}", id);
+            } else {
+                println!("No predicate named \"{}\".", id);
+            }
+        }
+        "reset" => {
+            env.reset();
+            if options.prelude {
+                load_prelude(env)?;
+            }
+            println!("All predicates are removed.");
+        }
+        "save" | "dump" => {
+            let file = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [file] => file,
+                _ => Err(ErrorKind::CommandUsage(format!(":{} FILE", name)))?,
+            };
+            let result = if name == "save" {
+                std::fs::File::create(file)
+                    .and_then(|writer| env.save_image(io::BufWriter::new(writer)))
+            } else {
+                std::fs::write(file, env.dump())
+            };
+            result.map_err(|err| Error::new(ErrorKind::Io(file.to_string())).with_source(err))?;
+            println!("Saved to {}.", file);
+        }
+        "consult_csv" | "consult_json" => {
+            let usage = || {
+                let header = if name == "consult_csv" {
+                    " [header]"
+                } else {
+                    ""
+                };
+                ErrorKind::CommandUsage(format!(":{} FILE as NAME/ARITY{}", name, header))
+            };
+            let (file, id, has_header) = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [file, "as", id] => (file, id, false),
+                [file, "as", id, "header"] if name == "consult_csv" => (file, id, true),
+                _ => Err(usage())?,
+            };
+            let id = parse_predicate_id(id)?;
+            let io_error = |err| Error::new(ErrorKind::Io(file.to_string())).with_source(err);
+            let count = if name == "consult_csv" {
+                let reader = std::fs::File::open(file).map_err(io_error)?;
+                load_csv(env, reader, &id, has_header)?
+            } else {
+                let text = std::fs::read_to_string(file).map_err(io_error)?;
+                load_json(env, &text, &id)?
+            };
+            let plural = if count == 1 { "" } else { "s" };
+            println!("Loaded {} fact{} into {}.", count, plural, id);
+        }
+        "export_csv" | "export_json" => {
+            let usage = || ErrorKind::CommandUsage(format!(":{} FILE [$VAR ...] ?QUERY", name));
+            let (head, _) = rest.split_once('?').ok_or_else(usage)?;
+            let (file, columns) = match head.split_whitespace().collect::<Vec<_>>()[..] {
+                [file, ref columns @ ..] => (file, columns.to_vec()),
+                [] => Err(usage())?,
+            };
+            let columns = columns
+                .iter()
+                .map(|column| column.strip_prefix('$').map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(usage)?;
+
+            let (_, stmt) = parse_single_query(LocatedSpan::new(&rest[head.len()..]))?;
+            let Statement::Query(mut stmt) = stmt else {
+                unreachable!()
+            };
+            env.qualify(USER_MODULE, &mut stmt.query)?;
+            let (relation, warnings) = query_relation(env, &mut stmt.query, &columns)?;
+            for warning in warnings {
+                report_warning(&warning, options);
+            }
+
+            // The file is written only when all the answers can be exported.
+            let result = if name == "export_csv" {
+                std::fs::File::create(file).and_then(|writer| relation.write_csv(writer))
+            } else {
+                let text = serde_json::to_string_pretty(&relation.to_json()).unwrap();
+                std::fs::write(file, text + "\n")
+            };
+            result.map_err(|err| Error::new(ErrorKind::Io(file.to_string())).with_source(err))?;
+            let plural = if relation.rows.len() == 1 { "" } else { "s" };
+            println!(
+                "Exported {} row{} to {}.",
+                relation.rows.len(),
+                plural,
+                file
+            );
+        }
+        _ => Err(ErrorKind::UnknownCommand(name.to_string()))?,
+    }
+    Ok(())
+}
+
+fn parse_predicate_id(text: &str) -> Result<PredicateId, ErrorKind> {
+    text.parse()
+        .map_err(|_| ErrorKind::InvalidPredicateId(text.trim().to_string()))
+}
+
+fn print_error(err: &Error) {
+    println!("ERR: {}", err);
+}
+
+///
+/// Prints an error, to the standard error in JSON so that the output stays parseable.
+///
+fn report_error(err: &Error, options: &Options) {
+    match options.format {
+        OutputFormat::Text => print_error(err),
+        OutputFormat::Json | OutputFormat::JsonLines => eprintln!("ERR: {}", err),
+    }
+}
+
+fn report_warning(warning: &WarningKind, options: &Options) {
+    match options.format {
+        OutputFormat::Text => print_warning(warning),
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            eprintln!("WARN: [{}] {}", warning.code(), warning)
+        }
+    }
+}
+
+fn print_warning(warning: &WarningKind) {
+    println!("WARN: [{}] {}", warning.code(), warning);
+}
+
+fn print_usage() {
+    println!("Usage: lonly [--unknown=fail|warning|error] [--strict-arity] [--compat] [--no-prelude] [--recover] [--format=text|json|jsonl] [--limit=N] [--image FILE] [--engine=interpreter|vm] [FILE]...");
+    println!("       lonly fmt [--check] [--width=N] [--compat] [FILE]...");
+    println!("       lonly test [--unknown=fail|warning|error] [--strict-arity] [--compat] [--no-prelude] [--image FILE] FILE...");
+}
+
+///
+/// Runs `lonly test`, which loads all the files and then runs the queries declared by `#test`.
+/// Returns the exit code, which is 1 if any test fails or any file is malformed.
+///
+fn run_tests(env: &mut Environment, files: &[String], options: &Options) -> i32 {
+    if let Err(err) = load_base(env, options) {
+        print_error(&err);
+        return 1;
+    }
+
+    let mut code = 0;
+    let mut tests = Vec::new();
+    for file in files {
+        let program = match std::fs::read_to_string(file) {
+            Ok(program) => program,
+            Err(err) => {
+                print_error(&Error::new(ErrorKind::Io(file.to_string())).with_source(err));
+                code = 1;
+                continue;
+            }
+        };
+        let (statements, errors) =
+            parse_program_located(LocatedSpan::new(&program), options.syntax);
+        for err in &errors {
+            print_error(err);
+            code = 1;
+        }
+
+        let mut module = USER_MODULE.to_string();
+        for (range, stmt) in statements {
+            let result = match stmt {
+                Statement::Def(stmt) => env.define(&module, stmt),
+                Statement::Directive(directive) => env.apply_directive(&mut module, directive),
+                Statement::Test(test) => {
+                    let line = program[..range.start].matches('\n').count() + 1;
+                    tests.push((format!("{}:{}", file, line), module.to_string(), test));
+                    Ok(())
                 }
-                ErrorKind::Parser(text) => {
-                    println!(
-                        "ERR: An error detected while parsing program. Detail: {}",
-                        text
+                Statement::Query(_) => Ok(()),
+            };
+            if let Err(err) = result {
+                print_error(&err.into());
+                code = 1;
+            }
+        }
+    }
+
+    let mut failures = Vec::new();
+    for (location, module, test) in &tests {
+        print!("test {} ?{} ... ", location, test.query);
+        match run_test(env, module, test) {
+            Ok(report) if report.passed() => println!("ok"),
+            Ok(report) => {
+                println!("FAILED");
+                failures.push((location, test, report.diff().join("\n")));
+            }
+            Err(err) => {
+                println!("FAILED");
+                failures.push((location, test, format!("ERR: {}", err)));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for (location, test, detail) in &failures {
+            println!("\n{} ?{}\n{}", location, test.query, detail);
+        }
+        code = 1;
+    }
+    let result = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed.",
+        result,
+        tests.len() - failures.len(),
+        failures.len()
+    );
+    code
+}
+
+///
+/// Runs `lonly fmt`, which rewrites the files in the canonical layout,
+/// or formats the standard input if no file is given.
+/// With `--check`, the files are left as they are and the ones which would change are listed.
+/// Returns the exit code.
+///
+fn run_fmt(args: &[String]) -> i32 {
+    let mut check = false;
+    let mut width = DEFAULT_WIDTH;
+    let mut syntax = Syntax::Terminated;
+    let mut files = Vec::new();
+    for arg in args {
+        if arg == "--check" {
+            check = true;
+        } else if let Some(value) = arg.strip_prefix("--width=") {
+            match value.parse() {
+                Ok(value) => width = value,
+                Err(_) => {
+                    print_usage();
+                    return 2;
+                }
+            }
+        } else if arg == "--compat" {
+            syntax = Syntax::Compat;
+        } else if arg.starts_with("--") {
+            print_usage();
+            return 2;
+        } else {
+            files.push(arg);
+        }
+    }
+
+    if files.is_empty() {
+        let mut program = String::new();
+        if let Err(err) = io::Read::read_to_string(&mut io::stdin(), &mut program) {
+            print_error(&Error::new(ErrorKind::Io("<stdin>".to_string())).with_source(err));
+            return 1;
+        }
+        return match format_program(&program, syntax, width) {
+            Ok(formatted) if check => (formatted != program) as i32,
+            Ok(formatted) => {
+                print!("{}", formatted);
+                0
+            }
+            Err(errors) => {
+                errors.iter().for_each(print_error);
+                1
+            }
+        };
+    }
+
+    let mut code = 0;
+    for file in files {
+        let program = match std::fs::read_to_string(file) {
+            Ok(program) => program,
+            Err(err) => {
+                print_error(&Error::new(ErrorKind::Io(file.to_string())).with_source(err));
+                code = 1;
+                continue;
+            }
+        };
+        let formatted = match format_program(&program, syntax, width) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                println!("{}:", file);
+                errors.iter().for_each(print_error);
+                code = 1;
+                continue;
+            }
+        };
+        if formatted == program {
+            continue;
+        }
+        if check {
+            println!("{}", file);
+            code = 1;
+        } else if let Err(err) = std::fs::write(file, formatted) {
+            print_error(&Error::new(ErrorKind::Io(file.to_string())).with_source(err));
+            code = 1;
+        }
+    }
+    code
+}
+
+///
+/// Checks whether the buffered input forms complete statements.
+///
+fn is_complete(buffer: &str, syntax: Syntax) -> bool {
+    match syntax {
+        // A comment may follow the terminator, while a `.` in a quoted atom is not one.
+        Syntax::Terminated => code_chars(buffer)
+            .filter(|(_, c)| !c.is_whitespace())
+            .last()
+            .is_none_or(|(_, c)| c == '.'),
+        Syntax::Compat => true,
+    }
+}
+
+///
+/// Returns the path of the file where the REPL history is kept.
+///
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".lonly_history"))
+}
+
+///
+/// Loads the image given by `--image`, or the prelude unless `--no-prelude` is given.
+/// An image replaces the prelude since it has the prelude loaded when it was saved.
+///
+fn load_base(env: &mut Environment, options: &Options) -> Result<(), Error> {
+    match &options.image {
+        Some(file) => {
+            let bytes = std::fs::read(file)
+                .map_err(|err| Error::new(ErrorKind::Io(file.to_string())).with_source(err))?;
+            env.load_image(&bytes)?;
+        }
+        None if options.prelude => load_prelude(env)?,
+        None => {}
+    }
+    Ok(())
+}
+
+///
+/// Reads the options of running or testing programs, and collects the files.
+/// Returns `None` if an option is unknown.
+///
+fn parse_args(args: Vec<String>, env: &mut Environment) -> Option<(Options, Vec<String>)> {
+    let mut options = Options {
+        syntax: Syntax::Terminated,
+        prelude: true,
+        recover: false,
+        format: OutputFormat::Text,
+        limit: None,
+        image: None,
+        engine: Engine::Interpreter,
+    };
+    let mut files = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if let Some(mode) = arg.strip_prefix("--unknown=") {
+            env.set_unknown(mode.parse::<UnknownMode>().ok()?);
+        } else if arg == "--strict-arity" {
+            env.set_strict_arity(true);
+        } else if arg == "--compat" {
+            options.syntax = Syntax::Compat;
+        } else if arg == "--no-prelude" {
+            options.prelude = false;
+        } else if arg == "--recover" {
+            options.recover = true;
+        } else if let Some(format) = arg.strip_prefix("--format=") {
+            options.format = format.parse().ok()?;
+        } else if let Some(limit) = arg.strip_prefix("--limit=") {
+            options.limit = Some(limit.parse().ok()?);
+        } else if let Some(engine) = arg.strip_prefix("--engine=") {
+            options.engine = engine.parse().ok()?;
+        } else if arg == "--image" {
+            options.image = Some(args.next()?);
+        } else if let Some(image) = arg.strip_prefix("--image=") {
+            options.image = Some(image.to_string());
+        } else if arg.starts_with("--") {
+            return None;
+        } else {
+            files.push(arg);
+        }
+    }
+    Some((options, files))
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().is_some_and(|arg| arg == "fmt") {
+        std::process::exit(run_fmt(&args[1..]));
+    }
+    let is_test = args.first().is_some_and(|arg| arg == "test");
+    if is_test {
+        args.remove(0);
+    }
+
+    let mut env = Environment::new();
+    let Some((options, files)) = parse_args(args, &mut env) else {
+        print_usage();
+        return;
+    };
+    if is_test {
+        std::process::exit(run_tests(&mut env, &files, &options));
+    }
+
+    if let Err(err) = load_base(&mut env, &options) {
+        report_error(&err, &options);
+        if options.image.is_some() {
+            std::process::exit(1);
+        }
+    }
+
+    if !files.is_empty() {
+        for file in &files {
+            match std::fs::read_to_string(file) {
+                Ok(program) => {
+                    if let Err(err) = exec_program(&mut env, &program, &options) {
+                        report_error(&err, &options);
+                    }
+                }
+                Err(err) => {
+                    report_error(
+                        &Error::new(ErrorKind::Io(file.to_string())).with_source(err),
+                        &options,
                     );
                 }
-                ErrorKind::UnknownInstruction => {
-                    println!("ERR: This option is not supported.")
+            }
+        }
+        for warning in env.check_undefined() {
+            report_warning(&warning, &options);
+        }
+    }
+
+    let mut editor = match Editor::<ReplHelper, DefaultHistory>::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            println!("ERR: Failed to initialize the line editor. Detail: {}", err);
+            return;
+        }
+    };
+    editor.set_helper(Some(ReplHelper::new()));
+    let history = history_path();
+    if let Some(history) = &history {
+        // The history file does not exist on the first run.
+        let _ = editor.load_history(history);
+    }
+
+    let mut buffer = String::new();
+    loop {
+        let predicates = env.predicate_ids().into_iter().cloned().collect();
+        editor.helper_mut().unwrap().set_predicates(predicates);
+
+        // Continuation lines are prompted differently.
+        let prompt = if buffer.is_empty() { "> " } else { "| " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                println!("ERR: Failed to read a line. Detail: {}", err);
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        if buffer.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                if let Err(err) = exec_command(&mut env, command, &options) {
+                    report_error(&err, &options);
                 }
+                continue;
             }
         }
+
+        buffer.push_str(&line);
+        buffer.push('\n');
+        if !is_complete(&buffer, options.syntax) {
+            continue;
+        }
+        let program = std::mem::take(&mut buffer);
+        if let Err(err) = exec_program(&mut env, &program, &options) {
+            report_error(&err, &options);
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            println!("ERR: Failed to save the history. Detail: {}", err);
+        }
     }
 }
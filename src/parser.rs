@@ -1,90 +1,507 @@
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while1},
-    character::complete::{multispace0, multispace1},
-    combinator::{eof, opt},
-    error::VerboseError,
-    multi::{separated_list0, separated_list1},
-    sequence::{delimited, tuple},
-    IResult,
+    character::complete::{digit1, multispace1, not_line_ending, satisfy},
+    combinator::{cut, eof, map, map_res, opt, peek, recognize},
+    error::{context, VerboseError},
+    multi::{many0, many1, separated_list0, separated_list1},
+    sequence::{delimited, preceded, terminated, tuple},
+    IResult, Slice,
 };
 use nom_locate::LocatedSpan;
+use std::ops::Range;
 
-use crate::ast::{AtomExpr, DefStatement, Expr, PredicateObj, QueryStatement, Statement, VarExpr};
+use crate::ast::{
+    AtomExpr, DefStatement, Directive, Expr, Goal, PredicateId, PredicateObj, QueryStatement,
+    Statement, TestStatement, VarExpr,
+};
+use crate::error::Error;
 
 type ParseResult<'a, T> = IResult<LocatedSpan<&'a str>, T, VerboseError<LocatedSpan<&'a str>>>;
 
-pub fn parse_program<'a>(program: LocatedSpan<&'a str>) -> ParseResult<Vec<Statement>> {
+///
+/// Decides how statements in a program are separated.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Every statement is terminated by `.` and may span multiple lines.
+    Terminated,
+    /// Statements are separated by whitespace, as in the early versions of lonly.
+    Compat,
+}
+
+pub fn parse_program<'a>(
+    program: LocatedSpan<&'a str>,
+    syntax: Syntax,
+) -> ParseResult<'a, Vec<Statement>> {
+    match syntax {
+        Syntax::Terminated => delimited(
+            blank0,
+            many0(terminated(
+                parse_statement,
+                tuple((parse_terminator, blank0)),
+            )),
+            context("a statement", eof),
+        )(program),
+        Syntax::Compat => delimited(
+            blank0,
+            separated_list0(blank1, parse_statement),
+            tuple((blank0, eof)),
+        )(program),
+    }
+}
+
+///
+/// Parses a text which consists of a single definition.
+///
+pub fn parse_single_def<'a>(program: LocatedSpan<&'a str>) -> ParseResult<'a, Statement> {
+    delimited(
+        blank0,
+        parse_def_statement,
+        tuple((blank0, opt(tag(".")), blank0, eof)),
+    )(program)
+}
+
+///
+/// Parses a text which consists of a single query.
+///
+pub fn parse_single_query<'a>(program: LocatedSpan<&'a str>) -> ParseResult<'a, Statement> {
     delimited(
-        multispace0,
-        separated_list0(multispace1, parse_statement),
-        tuple((multispace0, eof)),
+        blank0,
+        parse_query_statement,
+        tuple((blank0, opt(tag(".")), blank0, eof)),
     )(program)
 }
 
-fn parse_statement<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Statement> {
-    alt((parse_query_statement, parse_def_statement))(text)
+fn parse_terminator<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, LocatedSpan<&'a str>> {
+    preceded(blank0, context("\".\"", tag(".")))(text)
+}
+
+///
+/// Parses the statements one by one, and skips to the next statement when one is malformed.
+/// Returns the well-formed statements and the errors of the others.
+///
+pub fn parse_program_recovering(
+    program: LocatedSpan<&str>,
+    syntax: Syntax,
+) -> (Vec<Statement>, Vec<Error>) {
+    let (statements, errors) = parse_program_located(program, syntax);
+    let statements = statements.into_iter().map(|(_, stmt)| stmt).collect();
+    (statements, errors)
+}
+
+///
+/// Same as `parse_program_recovering`, but also returns the byte range where each statement is written.
+///
+pub fn parse_program_located(
+    program: LocatedSpan<&str>,
+    syntax: Syntax,
+) -> (Vec<(Range<usize>, Statement)>, Vec<Error>) {
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+    let mut text = skip_blank(program);
+    while !text.is_empty() {
+        match parse_next_statement(text, syntax) {
+            Ok((rest, stmt)) => {
+                statements.push((text.location_offset()..rest.location_offset(), stmt));
+                text = skip_blank(rest);
+            }
+            Err(err) => {
+                errors.push(Error::from(err));
+                text = skip_blank(skip_statement(text, syntax));
+            }
+        }
+    }
+    (statements, errors)
+}
+
+///
+/// Parses the statement at the beginning of the text up to its end,
+/// which is the terminator, or the end of the line in the compatible syntax.
+/// The whitespace and comments after the statement are left.
+///
+pub fn parse_next_statement(text: LocatedSpan<&str>, syntax: Syntax) -> ParseResult<'_, Statement> {
+    match syntax {
+        Syntax::Terminated => terminated(parse_statement, parse_terminator)(text),
+        Syntax::Compat => terminated(
+            parse_statement,
+            peek(context("a new line", alt((blank1, eof)))),
+        )(text),
+    }
+}
+
+///
+/// Skips whitespace and comments.
+///
+pub fn skip_blank(text: LocatedSpan<&str>) -> LocatedSpan<&str> {
+    blank0(text).map_or(text, |(rest, _)| rest)
+}
+
+///
+/// Skips to the end of the statement at the beginning of the text,
+/// which is the next `.` outside comments and quoted atoms, or the end of the line in the compatible syntax.
+///
+fn skip_statement(text: LocatedSpan<&str>, syntax: Syntax) -> LocatedSpan<&str> {
+    for (idx, c) in code_chars(text.fragment()) {
+        match c {
+            '\n' if syntax == Syntax::Compat => return text.slice(idx..),
+            '.' if syntax == Syntax::Terminated => return text.slice(idx + 1..),
+            _ => {}
+        }
+    }
+    text.slice(text.len()..)
+}
+
+///
+/// Iterates over the characters of the text with their offsets, leaving out comments and quoted atoms.
+/// Line breaks are always kept since they end both of them.
+///
+pub fn code_chars(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        Code,
+        Comment,
+        Quoted,
+        Escaped,
+    }
+    let mut state = State::Code;
+    text.char_indices().filter(move |&(_, c)| {
+        let (next, keep) = match (state, c) {
+            (_, '\n') => (State::Code, true),
+            (State::Code, '%') => (State::Comment, false),
+            (State::Code, '"') => (State::Quoted, false),
+            (State::Code, _) => (State::Code, true),
+            (State::Comment, _) => (State::Comment, false),
+            (State::Quoted, '\\') => (State::Escaped, false),
+            (State::Quoted, '"') => (State::Code, false),
+            (State::Quoted | State::Escaped, _) => (State::Quoted, false),
+        };
+        state = next;
+        keep
+    })
+}
+
+///
+/// Parses a comment, which starts with `%` and lasts until the end of the line.
+///
+fn parse_comment<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, LocatedSpan<&'a str>> {
+    recognize(tuple((tag("%"), not_line_ending)))(text)
+}
+
+///
+/// Skips whitespace and comments.
+///
+fn blank0<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, LocatedSpan<&'a str>> {
+    recognize(many0(alt((multispace1, parse_comment))))(text)
+}
+
+fn blank1<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, LocatedSpan<&'a str>> {
+    recognize(many1(alt((multispace1, parse_comment))))(text)
+}
+
+fn parse_statement<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Statement> {
+    alt((
+        parse_test_statement,
+        parse_directive,
+        parse_query_statement,
+        parse_def_statement,
+    ))(text)
+}
+
+fn parse_test_statement<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Statement> {
+    let (text, _) = tuple((tag("#test"), blank1))(text)?;
+    let (text, (unordered, _, query, _, answers)) = cut(tuple((
+        opt(tuple((tag("unordered"), blank1))),
+        tuple((context("a query", tag("?")), blank0)),
+        context("a query", parse_goal_predicate),
+        tuple((blank0, context("\"=>\"", tag("=>")), blank0)),
+        context("answers", parse_answers),
+    )))(text)?;
+    Ok((
+        text,
+        TestStatement::new(query, answers, unordered.is_none()),
+    ))
 }
 
-fn parse_query_statement<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Statement> {
-    let (text, (_, _, query)) = tuple((tag("?"), multispace0, parse_predicate))(text)?;
+///
+/// Parses the expected answers, which are `no` or `[$x = a, ...]` separated by `;`.
+///
+fn parse_answers<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Vec<Vec<(String, Expr)>>> {
+    alt((
+        map(tag("no"), |_| vec![]),
+        separated_list1(tuple((blank0, tag(";"), blank0)), parse_answer),
+    ))(text)
+}
+
+fn parse_answer<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Vec<(String, Expr)>> {
+    preceded(
+        tuple((tag("["), blank0)),
+        cut(terminated(
+            separated_list0(tuple((blank0, tag(","), blank0)), parse_binding),
+            preceded(blank0, context("\"]\" or \",\"", tag("]"))),
+        )),
+    )(text)
+}
+
+fn parse_binding<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, (String, Expr)> {
+    let (text, (_, name, _, expr)) = tuple((
+        tag("$"),
+        parse_ident,
+        tuple((blank0, tag("="), blank0)),
+        cut(context("a term", parse_expr)),
+    ))(text)?;
+    Ok((text, (name.to_string(), expr)))
+}
+
+fn parse_directive<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Statement> {
+    let (text, directive) = preceded(
+        tag("#"),
+        cut(context(
+            "a directive",
+            alt((
+                map(
+                    preceded(tuple((tag("module"), blank1)), parse_ident),
+                    |name| Directive::Module(name.to_string()),
+                ),
+                map(
+                    preceded(
+                        tuple((tag("export"), blank1)),
+                        separated_list1(tuple((blank0, tag(","), blank0)), parse_predicate_id),
+                    ),
+                    Directive::Export,
+                ),
+                map(
+                    preceded(tuple((tag("import"), blank1)), parse_ident),
+                    |name| Directive::Import(name.to_string()),
+                ),
+                map(
+                    preceded(
+                        tuple((tag("dynamic"), blank1)),
+                        separated_list1(tuple((blank0, tag(","), blank0)), parse_predicate_id),
+                    ),
+                    Directive::Dynamic,
+                ),
+            )),
+        )),
+    )(text)?;
+    Ok((text, Statement::Directive(directive)))
+}
+
+fn parse_predicate_id<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, PredicateId> {
+    let (text, (name, _, arity)) = tuple((
+        parse_ident,
+        tag("/"),
+        map_res(digit1, |digits: LocatedSpan<&'a str>| {
+            digits.parse::<usize>()
+        }),
+    ))(text)?;
+    Ok((text, PredicateId::new(name.to_string(), arity)))
+}
+
+fn parse_query_statement<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Statement> {
+    let (text, (_, _, query)) = tuple((
+        tag("?"),
+        blank0,
+        cut(context("a query", parse_goal_predicate)),
+    ))(text)?;
     Ok((text, QueryStatement::new(query)))
 }
 
-fn parse_def_statement<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Statement> {
+fn parse_def_statement<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Statement> {
     let (text, (conclusion, premises)) = tuple((
         parse_predicate,
         opt(tuple((
-            multispace0,
+            blank0,
             tag("<-"),
-            multispace0,
-            separated_list1(tuple((multispace0, tag(","), multispace0)), parse_predicate),
+            blank0,
+            cut(context("a premise", parse_disjunction)),
         ))),
     ))(text)?;
 
-    let premises = premises.map_or(vec![], |(_, _, _, premises)| premises);
+    // A conjunction at the top level is kept as a list of premises.
+    let premises = match premises {
+        Some((_, _, _, Goal::And(goals))) => goals,
+        Some((_, _, _, goal)) => vec![goal],
+        None => vec![],
+    };
     Ok((text, DefStatement::new(conclusion, premises)))
 }
 
+fn parse_disjunction<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Goal> {
+    let (text, alternatives) =
+        separated_list1(tuple((blank0, tag(";"), blank0)), parse_if_then)(text)?;
+
+    // `;` is right-associative, and `cond -> then` on its left takes the rest as the else branch.
+    let mut alternatives = alternatives.into_iter().rev();
+    let mut goal = match alternatives.next().unwrap() {
+        (cond, Some(then)) => Goal::IfThenElse(Box::new(cond), Box::new(then), None),
+        (goal, None) => goal,
+    };
+    for alternative in alternatives {
+        goal = match (alternative, goal) {
+            ((cond, Some(then)), goal) => {
+                Goal::IfThenElse(Box::new(cond), Box::new(then), Some(Box::new(goal)))
+            }
+            ((lhs, None), Goal::Or(mut goals)) => {
+                goals.insert(0, lhs);
+                Goal::Or(goals)
+            }
+            ((lhs, None), goal) => Goal::Or(vec![lhs, goal]),
+        };
+    }
+    Ok((text, goal))
+}
+
+fn parse_if_then<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, (Goal, Option<Goal>)> {
+    let (text, (cond, then)) = tuple((
+        parse_conjunction,
+        opt(tuple((blank0, tag("->"), blank0, parse_conjunction))),
+    ))(text)?;
+    Ok((text, (cond, then.map(|(_, _, _, then)| then))))
+}
+
+fn parse_conjunction<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Goal> {
+    let (text, mut goals) =
+        separated_list1(tuple((blank0, tag(","), blank0)), parse_primary_goal)(text)?;
+    if goals.len() == 1 {
+        Ok((text, goals.pop().unwrap()))
+    } else {
+        Ok((text, Goal::And(goals)))
+    }
+}
+
+fn parse_primary_goal<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Goal> {
+    alt((
+        preceded(
+            tuple((tag("("), blank0)),
+            cut(terminated(
+                parse_disjunction,
+                preceded(blank0, context("\")\"", tag(")"))),
+            )),
+        ),
+        map(parse_goal_predicate, Goal::Pred),
+    ))(text)
+}
+
+///
+/// Parses a predicate object which may be written with an infix operator such as `$x = $y`.
+///
+fn parse_goal_predicate<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, PredicateObj> {
+    alt((parse_infix_predicate, parse_predicate))(text)
+}
+
+fn parse_infix_predicate<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, PredicateObj> {
+    let (text, (lhs, _, op, _, rhs)) = tuple((
+        parse_expr,
+        blank0,
+        alt((tag("\\=="), tag("\\="), tag("=="), tag("="))),
+        blank0,
+        parse_expr,
+    ))(text)?;
+    Ok((text, PredicateObj::new(op.to_string(), vec![lhs, rhs])))
+}
+
 fn is_alphanumeric_or_underscore(s: char) -> bool {
     s.is_ascii_alphanumeric() || s == '_'
 }
 
-fn parse_ident<'a>(text: LocatedSpan<&'a str>) -> ParseResult<LocatedSpan<&'a str>> {
+fn parse_ident<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, LocatedSpan<&'a str>> {
     take_while1(is_alphanumeric_or_underscore)(text)
 }
 
-fn parse_n_ary<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Vec<Expr>> {
-    delimited(
-        tuple((tag("("), multispace0)),
-        separated_list1(tuple((multispace0, tag(","), multispace0)), parse_expr),
-        tuple((multispace0, tag(")"))),
+fn parse_n_ary<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Vec<Expr>> {
+    preceded(
+        tuple((tag("("), blank0)),
+        cut(terminated(
+            separated_list0(tuple((blank0, tag(","), blank0)), parse_expr),
+            preceded(blank0, context("\")\" or \",\"", tag(")"))),
+        )),
     )(text)
 }
 
-fn parse_predicate<'a>(text: LocatedSpan<&'a str>) -> ParseResult<PredicateObj> {
-    let (text, ident) = parse_ident(text)?;
-    let (text, l) = parse_n_ary(text)?;
-    Ok((text, PredicateObj::new(ident.to_string(), l)))
+///
+/// Parses the name of a predicate, which may be qualified by a module as `module:name`.
+///
+fn parse_predicate_name<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, LocatedSpan<&'a str>> {
+    recognize(tuple((opt(tuple((parse_ident, tag(":")))), parse_ident)))(text)
 }
 
-fn parse_expr<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Expr> {
-    alt((parse_var, parse_n_ary_atom, parse_nullary_atom))(text)
+fn parse_predicate<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, PredicateObj> {
+    let (text, ident) = parse_predicate_name(text)?;
+    let (text, l) = opt(parse_n_ary)(text)?;
+    Ok((
+        text,
+        PredicateObj::new(ident.to_string(), l.unwrap_or_default()),
+    ))
 }
 
-fn parse_var<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Expr> {
+fn parse_expr<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Expr> {
+    alt((
+        parse_var,
+        parse_list,
+        parse_quoted_atom,
+        parse_n_ary_atom,
+        parse_nullary_atom,
+    ))(text)
+}
+
+///
+/// Parses an atom whose name is quoted as `"New York"`, where `\"` and `\\` are escaped.
+/// The name may be followed by arguments as other atoms.
+///
+fn parse_quoted_atom<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Expr> {
+    let (text, chars) = preceded(
+        tag("\""),
+        cut(terminated(
+            many0(alt((
+                map(tag("\\\\"), |_| '\\'),
+                map(tag("\\\""), |_| '"'),
+                satisfy(|c| c != '"' && c != '\\' && c != '\n'),
+            ))),
+            context("a closing quotation mark", tag("\"")),
+        )),
+    )(text)?;
+    let (text, l) = opt(parse_n_ary)(text)?;
+    Ok((
+        text,
+        AtomExpr::new(chars.into_iter().collect::<String>(), l.unwrap_or_default()),
+    ))
+}
+
+///
+/// Parses `[a, b, ...]` or `[a, b, ... | tail]` into `[|]` cells terminated by `[]`.
+///
+fn parse_list<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Expr> {
+    let (text, (items, tail)) = preceded(
+        tuple((tag("["), blank0)),
+        cut(terminated(
+            tuple((
+                separated_list0(tuple((blank0, tag(","), blank0)), parse_expr),
+                opt(preceded(
+                    tuple((blank0, tag("|"), blank0)),
+                    context("a tail", parse_expr),
+                )),
+            )),
+            preceded(blank0, context("\"]\", \",\" or \"|\"", tag("]"))),
+        )),
+    )(text)?;
+    let tail = tail.unwrap_or_else(Expr::nil);
+    Ok((text, Expr::list(items, tail)))
+}
+
+fn parse_var<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Expr> {
     let (text, _) = tag("$")(text)?;
     let (text, ident) = parse_ident(text)?;
     Ok((text, VarExpr::new(ident.to_string())))
 }
 
-fn parse_n_ary_atom<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Expr> {
+fn parse_n_ary_atom<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Expr> {
     let (text, ident) = parse_ident(text)?;
     let (text, l) = parse_n_ary(text)?;
     Ok((text, AtomExpr::new(ident.to_string(), l)))
 }
 
-fn parse_nullary_atom<'a>(text: LocatedSpan<&'a str>) -> ParseResult<Expr> {
+fn parse_nullary_atom<'a>(text: LocatedSpan<&'a str>) -> ParseResult<'a, Expr> {
     let (text, ident) = parse_ident(text)?;
     Ok((text, AtomExpr::new(ident.to_string(), vec![])))
 }
@@ -114,6 +531,242 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_nullary_predicate_test() {
+        let parsed = parse_program(
+            LocatedSpan::new("raining.\nwet <- raining, cold().\n?wet."),
+            Syntax::Terminated,
+        );
+        assert!(parsed.is_ok());
+        let (_, items) = parsed.unwrap();
+        assert_eq!(
+            items,
+            vec![
+                DefStatement::new(PredicateObj::new("raining".to_string(), vec![]), vec![]),
+                DefStatement::new(
+                    PredicateObj::new("wet".to_string(), vec![]),
+                    vec![
+                        Goal::Pred(PredicateObj::new("raining".to_string(), vec![])),
+                        Goal::Pred(PredicateObj::new("cold".to_string(), vec![]))
+                    ]
+                ),
+                QueryStatement::new(PredicateObj::new("wet".to_string(), vec![]))
+            ]
+        );
+    }
+
+    #[test]
+    fn print_and_parse_test() {
+        let program = "add(s($x), $y, s($z)) <- add($x, $y, $z)\nready <- wet, num(s(z))\nsign($x, $s) <- (neg($x) -> $s = m ; (zero($x) ; small($x)) -> $s = z ; $s = p)\nnot_same($x, $y) <- $x \\== $y, f($x) \\= $y, dif($x, $y)";
+        let (_, items) = parse_program(LocatedSpan::new(program), Syntax::Compat).unwrap();
+        let printed = items
+            .iter()
+            .map(|item| match item {
+                Statement::Def(stmt) => stmt.to_string(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(printed, program);
+    }
+
+    #[test]
+    fn parse_goal_tree_test() {
+        let pred = |name: &str| Goal::Pred(PredicateObj::new(name.to_string(), vec![]));
+        let parsed = parse_program(
+            LocatedSpan::new(
+                "p <- a, (b ; c, d ; e).\nq <- (a -> b ; c -> d ; e), f.\nr <- a -> b.",
+            ),
+            Syntax::Terminated,
+        );
+        assert!(parsed.is_ok());
+        let (_, items) = parsed.unwrap();
+        assert_eq!(
+            items,
+            vec![
+                DefStatement::new(
+                    PredicateObj::new("p".to_string(), vec![]),
+                    vec![
+                        pred("a"),
+                        Goal::Or(vec![
+                            pred("b"),
+                            Goal::And(vec![pred("c"), pred("d")]),
+                            pred("e")
+                        ])
+                    ]
+                ),
+                DefStatement::new(
+                    PredicateObj::new("q".to_string(), vec![]),
+                    vec![
+                        Goal::IfThenElse(
+                            Box::new(pred("a")),
+                            Box::new(pred("b")),
+                            Some(Box::new(Goal::IfThenElse(
+                                Box::new(pred("c")),
+                                Box::new(pred("d")),
+                                Some(Box::new(pred("e")))
+                            )))
+                        ),
+                        pred("f")
+                    ]
+                ),
+                DefStatement::new(
+                    PredicateObj::new("r".to_string(), vec![]),
+                    vec![Goal::IfThenElse(
+                        Box::new(pred("a")),
+                        Box::new(pred("b")),
+                        None
+                    )]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_terminated_program_test() {
+        let program = "add(s($x), $y, s($z)) <-\n    add($x, $y, $z).\n?add($x, $y,\n  s(z)) .";
+        let parsed = parse_program(LocatedSpan::new(program), Syntax::Terminated);
+        assert!(parsed.is_ok());
+        let (_, items) = parsed.unwrap();
+        assert_eq!(items.len(), 2);
+
+        // A statement without the terminator is rejected.
+        let parsed = parse_program(LocatedSpan::new("num(z).\nnum(s(z))"), Syntax::Terminated);
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn parse_comment_test() {
+        let program =
+            "% Natural numbers.\nnum(z). % zero\nnum(s($n)) <- % successor\n    num($n).\n%";
+        let parsed = parse_program(LocatedSpan::new(program), Syntax::Terminated);
+        assert!(parsed.is_ok());
+        let (_, items) = parsed.unwrap();
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test]
+    fn parse_module_test() {
+        let program = "#module lists.\n#export append/3, member/2.\n#import peano.\n#dynamic seen/1.\n?lists:append($x, $y, []).";
+        let parsed = parse_program(LocatedSpan::new(program), Syntax::Terminated);
+        assert!(parsed.is_ok());
+        let (_, items) = parsed.unwrap();
+        assert_eq!(
+            items,
+            vec![
+                Statement::Directive(Directive::Module("lists".to_string())),
+                Statement::Directive(Directive::Export(vec![
+                    PredicateId::new("append".to_string(), 3),
+                    PredicateId::new("member".to_string(), 2)
+                ])),
+                Statement::Directive(Directive::Import("peano".to_string())),
+                Statement::Directive(Directive::Dynamic(vec![PredicateId::new(
+                    "seen".to_string(),
+                    1
+                )])),
+                QueryStatement::new(PredicateObj::new(
+                    "lists:append".to_string(),
+                    vec![
+                        VarExpr::new("x".to_string()),
+                        VarExpr::new("y".to_string()),
+                        Expr::nil()
+                    ]
+                ))
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_test_statement_test() {
+        let program = "#test ?add($x, $y, s(z)) => [$x = z, $y = s(z)]; [$x = s(z), $y = z].\n\
+            #test unordered ?p => [].\n\
+            #test ?q($x) => no.";
+        let (_, items) = parse_program(LocatedSpan::new(program), Syntax::Terminated).unwrap();
+        let displayed = items
+            .iter()
+            .map(|item| match item {
+                Statement::Test(test) => test.to_string(),
+                _ => panic!("{:?} is not a test", item),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            displayed,
+            vec![
+                "#test ?add($x, $y, s(z)) => [$x = z, $y = s(z)]; [$x = s(z), $y = z]",
+                "#test unordered ?p => []",
+                "#test ?q($x) => no",
+            ]
+        );
+        assert!(parse_program(LocatedSpan::new("#test ?p => [$x]."), Syntax::Terminated).is_err());
+    }
+
+    #[test]
+    fn parse_recovering_test() {
+        let program =
+            "num(z).\nnum(s($n) <- num($n).\nadd(z, $x, $x)\nok(a). % fine\np <- .\n?num(z).";
+        let (items, errors) =
+            parse_program_recovering(LocatedSpan::new(program), Syntax::Terminated);
+        assert_eq!(items.len(), 2);
+        let errors = errors
+            .iter()
+            .map(|err| (err.span().unwrap().line, err.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (2, "[E0001] Unexpected \"<- num($n).\" in the program; expected \")\" or \",\". (line 2, column 11)".to_string()),
+                (4, "[E0001] Unexpected \"ok(a). % fine\" in the program; expected \".\". (line 4, column 1)".to_string()),
+                (5, "[E0001] Unexpected \".\" in the program; expected a premise. (line 5, column 6)".to_string()),
+            ]
+        );
+
+        // The `.` in the quoted atom does not end the broken statement.
+        let (items, errors) = parse_program_recovering(
+            LocatedSpan::new("city(\"St. Louis\" <- .\ncity(paris)."),
+            Syntax::Terminated,
+        );
+        assert_eq!((items.len(), errors.len()), (1, 1));
+
+        let (items, errors) = parse_program_recovering(
+            LocatedSpan::new("num(z)\nnum(s(z)\n?num(z)"),
+            Syntax::Compat,
+        );
+        assert_eq!((items.len(), errors.len()), (2, 1));
+    }
+
+    #[test]
+    fn code_chars_test() {
+        let code = code_chars("p(\"a. \\\" %\"). % q.\n?p($x).")
+            .map(|(_, c)| c)
+            .collect::<String>();
+        assert_eq!(code, "p(). \n?p($x).");
+    }
+
+    #[test]
+    fn parse_list_test() {
+        let parsed = parse_expr(LocatedSpan::new("[a, [], [$x | $t]]"));
+        assert!(parsed.is_ok());
+        let (_, item) = parsed.unwrap();
+        assert_eq!(
+            item,
+            Expr::list(
+                vec![
+                    AtomExpr::new("a".to_string(), vec![]),
+                    Expr::nil(),
+                    Expr::cons(VarExpr::new("x".to_string()), VarExpr::new("t".to_string()))
+                ],
+                Expr::nil()
+            )
+        );
+        assert_eq!(item.to_string(), "[a, [], [$x | $t]]");
+
+        // Atoms named like list cells are not lists.
+        let (_, item) = parse_expr(LocatedSpan::new("cons(a, nil)")).unwrap();
+        assert!(item.as_list().is_none());
+        assert_eq!(item.to_string(), "cons(a, nil)");
+        assert_ne!(item, Expr::cons(AtomExpr::new("a", vec![]), Expr::nil()));
+    }
+
     #[test]
     fn parse_expr_test() {
         let parsed = parse_expr(LocatedSpan::new("test_1dent( s($x), $x)remains"));
@@ -131,4 +784,30 @@ mod test {
             )
         );
     }
+
+    #[test]
+    fn parse_quoted_atom_test() {
+        let (_, item) =
+            parse_statement(LocatedSpan::new(r#"?city("New York", "say \"hi\"", "")"#)).unwrap();
+        let Statement::Query(query) = &item else {
+            panic!("not a query: {:?}", item)
+        };
+        let names = query
+            .query
+            .arguments
+            .iter()
+            .map(|arg| match arg {
+                Expr::Atom(atom) => atom.name.clone(),
+                Expr::Var(_) => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["New York", "say \"hi\"", ""]);
+        // The names are quoted back when they are not identifiers.
+        assert_eq!(
+            query.query.to_string(),
+            r#"city("New York", "say \"hi\"", "")"#
+        );
+
+        assert!(parse_statement(LocatedSpan::new("?p(\"open)")).is_err());
+    }
 }
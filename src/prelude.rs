@@ -0,0 +1,142 @@
+use nom_locate::LocatedSpan;
+
+use crate::{
+    ast::{PredicateId, Statement},
+    env::Environment,
+    error::Error,
+    parser::{parse_program, Syntax},
+};
+
+///
+/// The sources of the prelude, which are embedded in the binary.
+///
+pub const PRELUDE: [(&str, &str); 3] = [
+    ("peano", include_str!("../lib/peano.lonly")),
+    ("lists", include_str!("../lib/lists.lonly")),
+    ("meta", include_str!("../lib/meta.lonly")),
+];
+
+///
+/// Loads the predicates of the prelude into the environment.
+/// The user may redefine them, which replaces the library clauses.
+///
+pub fn load_prelude(env: &mut Environment) -> Result<(), Error> {
+    for (_, source) in PRELUDE {
+        let (_, statements) = parse_program(LocatedSpan::new(source), Syntax::Terminated)?;
+        for stmt in statements {
+            if let Statement::Def(stmt) = stmt {
+                env.load_library(stmt)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// Lists the arguments of a meta-predicate of the prelude which are called as goals,
+/// each with the number of the arguments appended when it is called.
+/// Only the library clauses are declared, so a predicate the user defines with the same name is not affected.
+///
+pub fn meta_arguments(id: &PredicateId) -> Vec<(usize, usize)> {
+    match (id.name.as_str(), id.arity) {
+        ("maplist", 2..=4) => vec![(0, id.arity - 1)],
+        ("foldl", 4) => vec![(0, 3)],
+        ("include" | "exclude", 3) => vec![(0, 1)],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::evaluation::SolutionGenerator;
+
+    ///
+    /// Runs the query and prints the answers in the same way as the REPL,
+    /// except that the variables are sorted by their names.
+    ///
+    fn run_query(env: &mut Environment, query: &str, limit: usize) -> Vec<String> {
+        let (_, statements) = parse_program(LocatedSpan::new(query), Syntax::Terminated).unwrap();
+        let Some(Statement::Query(mut stmt)) = statements.into_iter().next() else {
+            panic!("\"{}\" is not a query", query);
+        };
+        let (mut solution_gen, mut name_table) =
+            SolutionGenerator::new(&mut stmt.query, env).unwrap();
+        name_table.sort();
+
+        if name_table.is_empty() {
+            let answer = match solution_gen.next().unwrap() {
+                Some(_) => "yes",
+                None => "no",
+            };
+            return vec![answer.to_string()];
+        }
+
+        let mut answers = Vec::new();
+        while answers.len() < limit {
+            let Some(solution) = solution_gen.next().unwrap() else {
+                break;
+            };
+            let bindings = name_table
+                .iter()
+                .filter_map(|(name, id)| Some(format!("{} = {}", name, solution.get(*id)?)))
+                .collect::<Vec<_>>();
+            answers.push(format!("[{}]", bindings.join(", ")));
+        }
+        answers
+    }
+
+    ///
+    /// Extracts the examples in the comments, each of which is a query
+    /// followed by the expected answers.
+    ///
+    fn examples(source: &str) -> Vec<(String, Vec<String>)> {
+        let mut examples: Vec<(String, Vec<String>)> = Vec::new();
+        for line in source.lines() {
+            let Some(line) = line.strip_prefix("%   ") else {
+                continue;
+            };
+            if line.starts_with('?') {
+                examples.push((line.to_string(), Vec::new()));
+            } else if let Some((_, answers)) = examples.last_mut() {
+                answers.push(line.to_string());
+            }
+        }
+        examples
+    }
+
+    #[test]
+    fn prelude_examples_test() {
+        let mut env = Environment::new();
+        load_prelude(&mut env).unwrap();
+        assert!(env.check_undefined().is_empty());
+
+        for (name, source) in PRELUDE {
+            let examples = examples(source);
+            assert!(!examples.is_empty(), "{} has no examples", name);
+            for (query, expected) in examples {
+                // One more answer is taken to check that no answer remains.
+                let answers = run_query(&mut env, &query, expected.len() + 1);
+                assert_eq!(answers, expected, "{}: {}", name, query);
+            }
+        }
+    }
+
+    #[test]
+    fn redefine_library_test() {
+        let mut env = Environment::new();
+        load_prelude(&mut env).unwrap();
+        let (_, statements) =
+            parse_program(LocatedSpan::new("num(one)."), Syntax::Terminated).unwrap();
+        for stmt in statements {
+            if let Statement::Def(stmt) = stmt {
+                env.update(stmt).unwrap();
+            }
+        }
+        assert_eq!(run_query(&mut env, "?num($n).", 2), vec!["[n = one]"]);
+        assert_eq!(
+            run_query(&mut env, "?length([a], $n).", 2),
+            vec!["[n = s(z)]"]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use rustyline::{
+    completion::{Completer, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Context, Helper,
+};
+
+use lonly::ast::PredicateId;
+
+const COMMANDS: [&str; 11] = [
+    "clear",
+    "consult_csv",
+    "consult_json",
+    "dump",
+    "export_csv",
+    "export_json",
+    "list",
+    "predicates",
+    "reset",
+    "retract",
+    "save",
+];
+
+///
+/// Completes command names and predicates known to the environment.
+///
+pub struct ReplHelper {
+    predicates: Vec<PredicateId>,
+}
+
+impl ReplHelper {
+    pub fn new() -> Self {
+        Self {
+            predicates: Vec::new(),
+        }
+    }
+
+    ///
+    /// Replaces the predicates offered as candidates.
+    ///
+    pub fn set_predicates(&mut self, predicates: Vec<PredicateId>) {
+        self.predicates = predicates;
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !is_ident_char(*c))
+            .map_or(0, |(idx, c)| idx + c.len_utf8());
+        let word = &line[start..pos];
+        let before = line[..start].trim_start();
+
+        // Variables are never completed.
+        if before.ends_with('$') {
+            return Ok((start, Vec::new()));
+        }
+
+        // Complete the name of a command.
+        if before == ":" {
+            let candidates = COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| Pair {
+                    display: command.to_string(),
+                    replacement: command.to_string(),
+                })
+                .collect();
+            return Ok((start, candidates));
+        }
+
+        // Commands take predicates in the form of `name/arity`.
+        let is_command = before.starts_with(':');
+        let candidates = self
+            .predicates
+            .iter()
+            .filter(|id| id.name.starts_with(word))
+            .map(|id| {
+                let replacement = if is_command {
+                    id.to_string()
+                } else if id.arity == 0 {
+                    id.name.to_string()
+                } else {
+                    format!("{}(", id.name)
+                };
+                Pair {
+                    display: id.to_string(),
+                    replacement,
+                }
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod test {
+    use rustyline::history::DefaultHistory;
+
+    use super::*;
+
+    fn complete(helper: &ReplHelper, line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+        let (start, candidates) = helper.complete(line, line.len(), &ctx).unwrap();
+        (
+            start,
+            candidates
+                .into_iter()
+                .map(|pair| pair.replacement)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn complete_test() {
+        let mut helper = ReplHelper::new();
+        helper.set_predicates(vec![
+            PredicateId::new("add".to_string(), 3),
+            PredicateId::new("num".to_string(), 1),
+            PredicateId::new("nil".to_string(), 0),
+        ]);
+        assert_eq!(
+            complete(&helper, "?add(z, $x, $x), n"),
+            (17, vec!["num(".to_string(), "nil".to_string()])
+        );
+        assert_eq!(
+            complete(&helper, ":list n"),
+            (6, vec!["num/1".to_string(), "nil/0".to_string()])
+        );
+        assert_eq!(
+            complete(&helper, ":pr"),
+            (1, vec!["predicates".to_string()])
+        );
+        assert_eq!(complete(&helper, "?num($n"), (6, vec![]));
+
+        // The word starts after a non-ASCII character, not inside it.
+        assert_eq!(
+            complete(&helper, "?é"),
+            (
+                3,
+                vec!["add(".to_string(), "num(".to_string(), "nil".to_string()]
+            )
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    ptr,
+    rc::Rc,
+};
+
+/// The fewest names kept before the unused ones are swept.
+const MIN_SWEEP_LEN: usize = 1024;
+
+///
+/// The names interned so far. A name is dropped by the next sweep once no symbol refers to it,
+/// so the names of variables renamed at runtime and of imported data do not pile up.
+///
+struct Interner {
+    names: HashSet<Rc<str>>,
+    /// The number of the names which triggers the next sweep.
+    sweep_len: usize,
+}
+
+thread_local! {
+    static SYMBOLS: RefCell<Interner> = RefCell::new(Interner {
+        names: HashSet::new(),
+        sweep_len: MIN_SWEEP_LEN,
+    });
+}
+
+///
+/// An interned name of an atom, a predicate or a variable.
+/// Every name in use is stored once in a table of the thread, so a symbol is cloned without allocating,
+/// and two symbols are compared and hashed by their addresses instead of their text.
+/// They are still ordered by their text so that sorted output does not depend on interning.
+/// A symbol belongs to the thread which interned it, so it is neither `Send` nor `Sync`.
+///
+#[derive(Clone)]
+pub struct Symbol(Rc<str>);
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        SYMBOLS.with_borrow_mut(|interner| {
+            if let Some(name) = interner.names.get(name) {
+                return Symbol(name.clone());
+            }
+            // Sweeping only when the table has doubled keeps interning amortized constant time.
+            if interner.names.len() >= interner.sweep_len {
+                interner.names.retain(|name| Rc::strong_count(name) > 1);
+                interner.sweep_len = (interner.names.len() * 2).max(MIN_SWEEP_LEN);
+            }
+            let name: Rc<str> = Rc::from(name);
+            interner.names.insert(name.clone());
+            Symbol(name)
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        ptr::hash(Rc::as_ptr(&self.0) as *const u8, state);
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::intern(&name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn intern_test() {
+        let name = String::from("New York");
+        let sym = Symbol::intern(&name);
+        assert_eq!(sym, Symbol::from("New York"));
+        assert!(ptr::eq(sym.as_str(), Symbol::from(name).as_str()));
+        assert_ne!(sym, Symbol::from("New"));
+        assert_eq!(sym, "New York");
+        let (a, b) = (Symbol::from("a"), Symbol::from("b"));
+        assert!(b > a);
+        assert_eq!(Symbol::from(""), Symbol::from(String::new()));
+    }
+
+    #[test]
+    fn sweep_test() {
+        let kept = Symbol::from("kept");
+        for idx in 0..MIN_SWEEP_LEN * 4 {
+            Symbol::intern(&format!("_{}", idx));
+        }
+        // The names no longer in use are dropped, while the others stay the same.
+        let len = SYMBOLS.with_borrow(|interner| interner.names.len());
+        assert!(len <= MIN_SWEEP_LEN * 2);
+        assert_eq!(kept, Symbol::from("kept"));
+    }
+}
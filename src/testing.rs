@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{AtomExpr, Expr, TestStatement, VarExpr},
+    env::Environment,
+    error::Error,
+    evaluation::SolutionGenerator,
+};
+
+///
+/// The answers which a `#test` expects and the ones which the query actually gave,
+/// both written as `[x = a, y = b]` with the variables sorted by their names.
+/// The answers are compared by their keys, which are the same for two answers differing only in the names of variables.
+///
+#[derive(Debug, PartialEq)]
+pub struct TestReport {
+    pub expected: Vec<String>,
+    pub actual: Vec<String>,
+    pub ordered: bool,
+    expected_keys: Vec<String>,
+    actual_keys: Vec<String>,
+}
+
+/// The key of an answer and the answer as it is written.
+type KeyedAnswer<'a> = (&'a String, &'a String);
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        let (expected, actual) = self.sorted();
+        expected
+            .iter()
+            .map(|(key, _)| key)
+            .eq(actual.iter().map(|(key, _)| key))
+    }
+
+    ///
+    /// Pairs the keys with the answers, sorting them by the keys if the test is unordered.
+    ///
+    fn sorted(&self) -> (Vec<KeyedAnswer<'_>>, Vec<KeyedAnswer<'_>>) {
+        let mut expected = self
+            .expected_keys
+            .iter()
+            .zip(&self.expected)
+            .collect::<Vec<_>>();
+        let mut actual = self
+            .actual_keys
+            .iter()
+            .zip(&self.actual)
+            .collect::<Vec<_>>();
+        if !self.ordered {
+            expected.sort();
+            actual.sort();
+        }
+        (expected, actual)
+    }
+
+    ///
+    /// Lists the answers, marking the ones only expected with `-` and the ones only found with `+`.
+    /// The answers of an unordered test are sorted first.
+    ///
+    pub fn diff(&self) -> Vec<String> {
+        let (expected, actual) = self.sorted();
+
+        // `lengths[i][j]` is the length of the longest common subsequence of `expected[i..]` and `actual[j..]`.
+        let mut lengths = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+        for i in (0..expected.len()).rev() {
+            for j in (0..actual.len()).rev() {
+                lengths[i][j] = if expected[i].0 == actual[j].0 {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+
+        let mut lines = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < expected.len() || j < actual.len() {
+            if i < expected.len() && j < actual.len() && expected[i].0 == actual[j].0 {
+                lines.push(format!("  {}", actual[j].1));
+                i += 1;
+                j += 1;
+            } else if i < expected.len()
+                && (j == actual.len() || lengths[i + 1][j] >= lengths[i][j + 1])
+            {
+                lines.push(format!("- {}", expected[i].1));
+                i += 1;
+            } else {
+                lines.push(format!("+ {}", actual[j].1));
+                j += 1;
+            }
+        }
+        lines
+    }
+}
+
+///
+/// Replaces the variables in the expression with `$_0`, `$_1`, ... in the order of their first appearance,
+/// continuing the numbering of the variables already seen in `vars`.
+///
+pub(crate) fn rename_vars(expr: &Expr, vars: &mut Vec<VarExpr>) -> Expr {
+    match expr {
+        Expr::Atom(atom) => AtomExpr::new(
+            atom.name.clone(),
+            atom.arguments
+                .iter()
+                .map(|arg| rename_vars(arg, vars))
+                .collect(),
+        ),
+        Expr::Var(var) => {
+            let idx = vars
+                .iter()
+                .position(|other| other == var)
+                .unwrap_or_else(|| {
+                    vars.push(var.clone());
+                    vars.len() - 1
+                });
+            VarExpr::new(format!("_{}", idx))
+        }
+    }
+}
+
+///
+/// Writes the values of the query variables with the variables in them numbered in the order of appearance,
+/// so that the keys of two answers are the same if they differ only in the names of variables.
+///
+fn variant_key(values: &[Expr]) -> String {
+    let mut vars = Vec::new();
+    let values = values
+        .iter()
+        .map(|value| rename_vars(value, &mut vars).to_string())
+        .collect::<Vec<_>>();
+    format!("[{}]", values.join(", "))
+}
+
+///
+/// Runs the query of the test written in the module.
+/// One more answer than expected is taken so that an extra answer is reported,
+/// but no more since the query may have infinitely many answers.
+///
+pub fn run_test(
+    env: &mut Environment,
+    module: &str,
+    test: &TestStatement,
+) -> Result<TestReport, Error> {
+    let mut query = test.query.clone();
+    env.qualify(module, &mut query)?;
+    let (mut solution_gen, mut name_table) = SolutionGenerator::new(&mut query, env)?;
+    name_table.sort();
+
+    // A variable of the query which is not bound in an expected answer stands for itself,
+    // and a binding of a variable not in the query never matches.
+    let mut expected = Vec::new();
+    let mut expected_keys = Vec::new();
+    for bindings in &test.answers {
+        let mut written = bindings
+            .iter()
+            .map(|(name, expr)| format!("{} = {}", name, expr))
+            .collect::<Vec<_>>();
+        written.sort();
+        expected.push(format!("[{}]", written.join(", ")));
+
+        let bound = bindings.iter().cloned().collect::<HashMap<_, _>>();
+        let values = name_table
+            .iter()
+            .map(|(name, _)| {
+                bound
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_else(|| VarExpr::new(name))
+            })
+            .collect::<Vec<_>>();
+        let mut key = variant_key(&values);
+        for (name, expr) in bindings {
+            if !name_table.iter().any(|(other, _)| other == name) {
+                key += &format!(" {} = {}", name, expr);
+            }
+        }
+        expected_keys.push(key);
+    }
+
+    // Variables left unbound are omitted as in the REPL.
+    let mut actual = Vec::new();
+    let mut actual_keys = Vec::new();
+    while actual.len() <= expected.len() {
+        let Some(solution) = solution_gen.next()? else {
+            break;
+        };
+        let bindings = name_table
+            .iter()
+            .filter_map(|(name, id)| Some(format!("{} = {}", name, solution.get(*id)?)))
+            .collect::<Vec<_>>();
+        actual.push(format!("[{}]", bindings.join(", ")));
+
+        let values = name_table
+            .iter()
+            .map(|(name, id)| {
+                solution.get(*id).cloned().unwrap_or_else(|| {
+                    Expr::Var(VarExpr {
+                        name: name.into(),
+                        id: Some(*id),
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        actual_keys.push(variant_key(&values));
+    }
+
+    Ok(TestReport {
+        expected,
+        actual,
+        ordered: test.ordered,
+        expected_keys,
+        actual_keys,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ast::Statement, env::run_program, prelude::load_prelude};
+
+    fn run(program: &str) -> Vec<TestReport> {
+        let mut env = Environment::new();
+        load_prelude(&mut env).unwrap();
+        let mut reports = Vec::new();
+        run_program(&mut env, program, |env, module, stmt| {
+            if let Statement::Test(test) = stmt {
+                reports.push(run_test(env, module, &test).unwrap());
+            }
+        });
+        reports
+    }
+
+    #[test]
+    fn run_test_test() {
+        let reports = run("\
+            #test ?add($x, $y, s(z)) => [$y = s(z), $x = z]; [$x = s(z), $y = z].\n\
+            #test ?add($x, $y, s(z)) => [$x = s(z), $y = z]; [$x = z, $y = s(z)].\n\
+            #test unordered ?add($x, $y, s(z)) => [$x = s(z), $y = z]; [$x = z, $y = s(z)].\n\
+            #test ?num(z) => [].\n\
+            #test ?num(a) => no.\n\
+            #test ?num($n) => [$n = z].\n\
+            wrap($x, f($x)).\n\
+            #test ?wrap($a, $b) => [$b = f($a)].\n\
+            #test ?wrap($a, $b) => [$a = $y, $b = f($y)].\n\
+            #test ?wrap($a, $b) => [$b = f($c)].\n\
+            #test ?wrap($a, $a) => no.");
+        let passed = reports.iter().map(TestReport::passed).collect::<Vec<_>>();
+        assert_eq!(
+            passed,
+            vec![true, false, true, true, true, false, true, true, false, true]
+        );
+
+        assert_eq!(
+            reports[1].diff(),
+            vec![
+                "- [x = s(z), y = z]",
+                "  [x = z, y = s(z)]",
+                "+ [x = s(z), y = z]",
+            ]
+        );
+        // An extra answer is reported.
+        assert_eq!(reports[5].diff(), vec!["  [n = z]", "+ [n = s(z)]"]);
+    }
+}
@@ -12,6 +12,26 @@ fn var_occurs(expr: &Expr, id: VarID) -> bool {
     }
 }
 
+///
+/// Checks whether two expressions are the same term without binding any variables.
+///
+pub fn identical(lhs: &Expr, rhs: &Expr) -> bool {
+    match (lhs, rhs) {
+        (Expr::Atom(lhs), Expr::Atom(rhs)) => {
+            lhs.name == rhs.name
+                && lhs.arguments.len() == rhs.arguments.len()
+                && lhs
+                    .arguments
+                    .iter()
+                    .zip(rhs.arguments.iter())
+                    .all(|(lhs, rhs)| identical(lhs, rhs))
+        }
+        (Expr::Var(lhs), Expr::Var(rhs)) => lhs.id.is_some() && lhs.id == rhs.id,
+        _ => false,
+    }
+}
+
+#[allow(clippy::result_unit_err)]
 pub fn unify_exprs(exprs: &mut VecDeque<(Expr, Expr)>) -> Result<VarSubstitution, ()> {
     if let Some((expr1, expr2)) = exprs.pop_front() {
         match (&expr1, &expr2) {
@@ -0,0 +1,1497 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ast::{AtomExpr, Expr, Goal, PredicateId, PredicateObj, VarExpr, VarID},
+    builtins::Builtin,
+    env::{Environment, UnknownMode, VarAllocator, VarSubstitution},
+    error::{ErrorKind, WarningKind},
+    evaluation::Solver,
+    symbol::Symbol,
+};
+
+/// The index of a name in the symbol table of the machine.
+type Sym = u32;
+
+///
+/// A cell of the heap or a register.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    /// A variable, which is unbound if it refers to itself.
+    Ref(usize),
+    /// A compound term whose functor is at the address.
+    Str(usize),
+    /// The functor of a compound term, followed by its arguments on the heap.
+    Functor(Sym, usize),
+    /// An atom without arguments, including integers.
+    Con(Sym),
+}
+
+///
+/// A temporary register, or a permanent one in the environment frame of the clause.
+///
+#[derive(Debug, Clone, Copy)]
+enum Reg {
+    X(usize),
+    Y(usize),
+}
+
+///
+/// The built-in predicates run by the machine itself.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Unify,
+    NotUnifiable,
+    Identical,
+    NotIdentical,
+}
+
+impl Op {
+    fn lookup(builtin: Builtin) -> Option<Op> {
+        match builtin {
+            Builtin::Unify => Some(Op::Unify),
+            Builtin::NotUnifiable => Some(Op::NotUnifiable),
+            Builtin::Identical => Some(Op::Identical),
+            Builtin::NotIdentical => Some(Op::NotIdentical),
+            _ => None,
+        }
+    }
+}
+
+///
+/// The instructions of the machine. Argument registers are the first temporary registers,
+/// so `usize` operands are indices of temporary registers.
+///
+#[derive(Debug, Clone, Copy)]
+enum Instr {
+    GetVariable(Reg, usize),
+    GetValue(Reg, usize),
+    GetConstant(Sym, usize),
+    GetStructure(Sym, usize, usize),
+    UnifyVariable(Reg),
+    UnifyValue(Reg),
+    UnifyConstant(Sym),
+    PutVariable(Reg, usize),
+    PutValue(Reg, usize),
+    PutConstant(Sym, usize),
+    PutStructure(Sym, usize, usize),
+    SetVariable(Reg),
+    SetValue(Reg),
+    SetConstant(Sym),
+    /// Pushes an environment frame with the number of permanent registers.
+    Allocate(usize),
+    Deallocate,
+    /// Calls the predicate of the index, returning to the next instruction.
+    Call(usize),
+    /// Jumps to the predicate of the index as the last goal of a clause.
+    Execute(usize),
+    /// `call/N` with the number of its arguments.
+    CallMeta(usize),
+    ExecuteMeta(usize),
+    Builtin(Op),
+    Proceed,
+    /// Pushes a choice point which resumes at the address, saving the number of arguments.
+    TryMeElse(usize, usize),
+    RetryMeElse(usize),
+    TrustMe,
+    /// Removes the choice points made since the clause was called.
+    Cut,
+    Fail,
+    /// Calls the predicate of the index, which has no clauses.
+    Undefined(usize),
+    /// Reports an answer of the query.
+    Halt,
+}
+
+///
+/// A step of a clause body after the control constructs are compiled away.
+///
+#[derive(Debug, Clone)]
+enum Step {
+    Call(PredicateObj),
+    Cut,
+}
+
+#[derive(Debug, Clone)]
+struct FlatClause {
+    head: PredicateObj,
+    body: Vec<Step>,
+}
+
+///
+/// The goals of a clause body before flattening, where `Cut` is inserted by if-then-else.
+///
+enum BodyItem {
+    Goal(Goal),
+    Cut,
+}
+
+struct Frame {
+    /// The frame of the continuation.
+    ce: usize,
+    cp: usize,
+    /// The number of the choice points when the clause was called, to which `Cut` returns.
+    b0: usize,
+    /// The start of the permanent registers in `Machine::ys`.
+    y_base: usize,
+}
+
+struct Choice {
+    arity: usize,
+    /// The start of the saved arguments in `Machine::saved`.
+    args_base: usize,
+    e: usize,
+    cp: usize,
+    b0: usize,
+    next: usize,
+    trail_len: usize,
+    heap_len: usize,
+    frames_len: usize,
+    ys_len: usize,
+}
+
+/// The frame index meaning no frame.
+const NO_FRAME: usize = usize::MAX;
+/// The address of `Instr::Halt`, which is the continuation of the query.
+const HALT: usize = 0;
+
+///
+/// Compiles the clauses of the environment into instructions for an abstract machine
+/// in the style of the WAM, and runs a query on it.
+/// Unlike the interpreter, the search is depth-first, so the answers may come in another order,
+/// and a left-recursive predicate may loop forever.
+/// Predicates are compiled when they are first reached from the query.
+///
+pub struct Machine<'a> {
+    env: &'a Environment,
+
+    symbols: Vec<Symbol>,
+    symbol_ids: HashMap<Symbol, Sym>,
+    preds: Vec<PredicateId>,
+    pred_ids: HashMap<PredicateId, usize>,
+    entries: Vec<Option<usize>>,
+    /// Auxiliary predicates made from disjunctions and if-then-else, which are not compiled yet.
+    aux_clauses: HashMap<usize, Vec<FlatClause>>,
+    pending: Vec<usize>,
+    code: Vec<Instr>,
+    num_regs: usize,
+
+    heap: Vec<Cell>,
+    x: Vec<Cell>,
+    frames: Vec<Frame>,
+    ys: Vec<Cell>,
+    choices: Vec<Choice>,
+    saved: Vec<Cell>,
+    trail: Vec<usize>,
+    /// The heap size at the last choice point. Bindings of older cells are trailed.
+    hb: usize,
+    p: usize,
+    cp: usize,
+    e: usize,
+    b0: usize,
+    write_mode: bool,
+    s: usize,
+    /// The structure being built by `GetStructure` for a bound variable, and its nested structures.
+    /// `UnifyValue` rejects a term containing them, which would make a cyclic term.
+    guards: Vec<usize>,
+
+    query_vars: Vec<(Symbol, VarID)>,
+    started: bool,
+    exhausted: bool,
+    warnings: Vec<WarningKind>,
+    reported: HashSet<PredicateId>,
+}
+
+impl<'a> Machine<'a> {
+    ///
+    /// Compiles the query and the predicates which it reaches.
+    /// Fails with `ErrorKind::UnsupportedByMachine` if one of them calls a built-in predicate
+    /// which the machine does not run.
+    ///
+    pub fn new(
+        query: &mut PredicateObj,
+        env: &'a Environment,
+    ) -> Result<(Self, Vec<(String, VarID)>), ErrorKind> {
+        let mut var_alloc = VarAllocator::new();
+        let mut assigned = HashMap::new();
+        var_alloc.assign_new_ids(&mut query.arguments, &mut assigned)?;
+        let mut query_vars = assigned.into_iter().collect::<Vec<_>>();
+        query_vars.sort_by_key(|(_, id)| *id);
+
+        let mut machine = Machine {
+            env,
+            symbols: Vec::new(),
+            symbol_ids: HashMap::new(),
+            preds: Vec::new(),
+            pred_ids: HashMap::new(),
+            entries: Vec::new(),
+            aux_clauses: HashMap::new(),
+            pending: Vec::new(),
+            code: vec![Instr::Halt],
+            num_regs: 0,
+            heap: Vec::new(),
+            x: Vec::new(),
+            frames: Vec::new(),
+            ys: Vec::new(),
+            choices: Vec::new(),
+            saved: Vec::new(),
+            trail: Vec::new(),
+            hb: 0,
+            p: HALT,
+            cp: HALT,
+            e: NO_FRAME,
+            b0: 0,
+            write_mode: false,
+            s: 0,
+            guards: Vec::new(),
+            query_vars: query_vars.clone(),
+            started: false,
+            exhausted: false,
+            warnings: Vec::new(),
+            reported: HashSet::new(),
+        };
+
+        // The query is compiled as the clause `$query(Vars...) <- Query.`
+        let head = PredicateObj::new(
+            "$query".to_string(),
+            query_vars
+                .iter()
+                .map(|(name, _)| VarExpr::new(name.clone()))
+                .collect(),
+        );
+        let clause = FlatClause {
+            head,
+            body: vec![Step::Call(query.clone())],
+        };
+        let idx = machine.aux_predicate(query_vars.len(), vec![clause]);
+        machine.compile_pending()?;
+
+        for idx in 0..query_vars.len() {
+            machine.heap.push(Cell::Ref(idx));
+            machine.x[idx] = Cell::Ref(idx);
+        }
+        machine.p = machine.entries[idx].unwrap();
+        let name_table = query_vars
+            .into_iter()
+            .map(|(name, id)| (name.to_string(), id))
+            .collect();
+        Ok((machine, name_table))
+    }
+
+    ///
+    /// Finds the next answer, which binds the variables of the query by their IDs.
+    ///
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<VarSubstitution>, ErrorKind> {
+        if self.exhausted {
+            return Ok(None);
+        }
+        // The search resumes from the last choice point after an answer.
+        if self.started && !self.backtrack() {
+            self.exhausted = true;
+            return Ok(None);
+        }
+        self.started = true;
+        match self.run() {
+            Ok(true) => Ok(Some(self.answer())),
+            Ok(false) => {
+                self.exhausted = true;
+                Ok(None)
+            }
+            Err(err) => {
+                self.exhausted = true;
+                Err(err)
+            }
+        }
+    }
+
+    ///
+    /// Takes the warnings reported since the last call.
+    ///
+    pub fn take_warnings(&mut self) -> Vec<WarningKind> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn symbol(&mut self, name: &Symbol) -> Sym {
+        if let Some(sym) = self.symbol_ids.get(name) {
+            return *sym;
+        }
+        let sym = self.symbols.len() as Sym;
+        self.symbols.push(name.clone());
+        self.symbol_ids.insert(name.clone(), sym);
+        sym
+    }
+
+    ///
+    /// Returns the index of the predicate, which is compiled later if it is new.
+    ///
+    fn predicate(&mut self, id: PredicateId) -> usize {
+        if let Some(idx) = self.pred_ids.get(&id) {
+            return *idx;
+        }
+        let idx = self.preds.len();
+        self.preds.push(id.clone());
+        self.pred_ids.insert(id, idx);
+        self.entries.push(None);
+        self.pending.push(idx);
+        idx
+    }
+
+    fn aux_predicate(&mut self, arity: usize, clauses: Vec<FlatClause>) -> usize {
+        let name = format!("$aux{}", self.preds.len());
+        let idx = self.predicate(PredicateId::new(name, arity));
+        self.aux_clauses.insert(idx, clauses);
+        idx
+    }
+
+    fn compile_pending(&mut self) -> Result<(), ErrorKind> {
+        while let Some(idx) = self.pending.pop() {
+            if self.entries[idx].is_none() {
+                self.compile_predicate(idx)?;
+            }
+        }
+        if self.x.len() < self.num_regs {
+            self.x.resize(self.num_regs, Cell::Ref(0));
+        }
+        Ok(())
+    }
+
+    fn compile_predicate(&mut self, idx: usize) -> Result<(), ErrorKind> {
+        let id = self.preds[idx].clone();
+        let clauses = match self.aux_clauses.remove(&idx) {
+            Some(clauses) => clauses,
+            None => {
+                self.env.check_calls(&id)?;
+                let rules = self.env.get_rules(&id).cloned().unwrap_or_default();
+                rules
+                    .into_iter()
+                    .map(|(conclusion, premises)| {
+                        let body = premises.into_iter().map(BodyItem::Goal).collect();
+                        self.flatten(conclusion, body)
+                    })
+                    .collect()
+            }
+        };
+
+        self.entries[idx] = Some(self.code.len());
+        if clauses.is_empty() {
+            // A dynamic predicate fails silently.
+            let instr = if self.env.is_defined(&id) {
+                Instr::Fail
+            } else {
+                Instr::Undefined(idx)
+            };
+            self.code.push(instr);
+            return Ok(());
+        }
+
+        let num_clauses = clauses.len();
+        for (clause_idx, clause) in clauses.iter().enumerate() {
+            let label = self.code.len();
+            if num_clauses > 1 {
+                self.code.push(match clause_idx {
+                    0 => Instr::TryMeElse(0, id.arity),
+                    idx if idx + 1 < num_clauses => Instr::RetryMeElse(0),
+                    _ => Instr::TrustMe,
+                });
+            }
+            self.compile_clause(clause)?;
+            // The alternative is the next clause.
+            let next = self.code.len();
+            match &mut self.code[label] {
+                Instr::TryMeElse(alt, _) | Instr::RetryMeElse(alt) => *alt = next,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Flattens the conjunctions in the body, and replaces each disjunction and if-then-else
+    /// with a call of an auxiliary predicate, whose arguments are the variables shared with the rest.
+    /// `(C -> T ; E)` becomes the clauses `aux <- C, !, T.` and `aux <- E.`
+    ///
+    fn flatten(&mut self, head: PredicateObj, items: Vec<BodyItem>) -> FlatClause {
+        let mut flat = Vec::new();
+        for item in items {
+            flatten_and(item, &mut flat);
+        }
+
+        let mut body = Vec::new();
+        for (idx, item) in flat.iter().enumerate() {
+            let goal = match item {
+                BodyItem::Cut => {
+                    body.push(Step::Cut);
+                    continue;
+                }
+                BodyItem::Goal(Goal::Pred(goal)) => {
+                    body.push(Step::Call(goal.clone()));
+                    continue;
+                }
+                BodyItem::Goal(goal) => goal,
+            };
+
+            let mut outside = Vec::new();
+            head.arguments
+                .iter()
+                .for_each(|arg| expr_vars(arg, &mut outside));
+            for (other_idx, other) in flat.iter().enumerate() {
+                if let (true, BodyItem::Goal(other)) = (other_idx != idx, other) {
+                    goal_vars(other, &mut outside);
+                }
+            }
+            let mut shared = Vec::new();
+            goal_vars(goal, &mut shared);
+            shared.retain(|name| outside.contains(name));
+
+            let aux_head = PredicateObj::new(
+                String::new(),
+                shared
+                    .iter()
+                    .map(|name| VarExpr::new(name.clone()))
+                    .collect(),
+            );
+            let bodies = match goal {
+                Goal::Or(branches) => branches
+                    .iter()
+                    .map(|branch| vec![BodyItem::Goal(branch.clone())])
+                    .collect(),
+                Goal::IfThenElse(cond, then, otherwise) => {
+                    let mut bodies = vec![vec![
+                        BodyItem::Goal(*cond.clone()),
+                        BodyItem::Cut,
+                        BodyItem::Goal(*then.clone()),
+                    ]];
+                    if let Some(otherwise) = otherwise {
+                        bodies.push(vec![BodyItem::Goal(*otherwise.clone())]);
+                    }
+                    bodies
+                }
+                Goal::Pred(_) | Goal::And(_) => unreachable!(),
+            };
+            let clauses = bodies
+                .into_iter()
+                .map(|items| self.flatten(aux_head.clone(), items))
+                .collect();
+            let aux_idx = self.aux_predicate(shared.len(), clauses);
+            let mut call = aux_head;
+            call.name = self.preds[aux_idx].name.clone();
+            for clause in self.aux_clauses.get_mut(&aux_idx).unwrap() {
+                clause.head.name = call.name.clone();
+            }
+            body.push(Step::Call(call));
+        }
+        FlatClause { head, body }
+    }
+
+    fn compile_clause(&mut self, clause: &FlatClause) -> Result<(), ErrorKind> {
+        let num_calls = clause
+            .body
+            .iter()
+            .filter(|step| matches!(step, Step::Call(_)))
+            .count();
+        let has_cut = clause.body.iter().any(|step| matches!(step, Step::Cut));
+        let allocate = num_calls >= 2 || has_cut;
+
+        // A variable is permanent if it appears in more than one chunk,
+        // which are the head with the first goal and each of the other goals.
+        let mut chunks: HashMap<Symbol, (usize, bool)> = HashMap::new();
+        let mut note = |name: Symbol, chunk: usize| {
+            let entry = chunks.entry(name).or_insert((chunk, false));
+            if entry.0 != chunk {
+                entry.1 = true;
+            }
+        };
+        let mut names = Vec::new();
+        clause
+            .head
+            .arguments
+            .iter()
+            .for_each(|arg| expr_vars(arg, &mut names));
+        names.into_iter().for_each(|name| note(name, 0));
+        let mut num_arguments = clause.head.arguments.len();
+        for (chunk, goal) in clause
+            .body
+            .iter()
+            .filter_map(|step| match step {
+                Step::Call(goal) => Some(goal),
+                Step::Cut => None,
+            })
+            .enumerate()
+        {
+            let mut names = Vec::new();
+            goal.arguments
+                .iter()
+                .for_each(|arg| expr_vars(arg, &mut names));
+            names.into_iter().for_each(|name| note(name, chunk));
+            num_arguments = num_arguments.max(goal.arguments.len());
+        }
+
+        let mut compiler = ClauseCompiler {
+            vars: HashMap::new(),
+            next_x: num_arguments,
+            code: Vec::new(),
+        };
+        let mut num_permanent = 0;
+        let mut all_names = Vec::new();
+        clause
+            .head
+            .arguments
+            .iter()
+            .for_each(|arg| expr_vars(arg, &mut all_names));
+        for step in &clause.body {
+            if let Step::Call(goal) = step {
+                goal.arguments
+                    .iter()
+                    .for_each(|arg| expr_vars(arg, &mut all_names));
+            }
+        }
+        for name in all_names {
+            if allocate && chunks[&name].1 {
+                compiler.vars.insert(name, (Reg::Y(num_permanent), false));
+                num_permanent += 1;
+            }
+        }
+
+        if allocate {
+            compiler.code.push(Instr::Allocate(num_permanent));
+        }
+        compiler.get_arguments(self, &clause.head);
+
+        let mut ends_with_jump = false;
+        for (idx, step) in clause.body.iter().enumerate() {
+            let is_last = idx + 1 == clause.body.len();
+            let goal = match step {
+                Step::Cut => {
+                    compiler.code.push(Instr::Cut);
+                    continue;
+                }
+                Step::Call(goal) => goal,
+            };
+            compiler.put_arguments(self, goal);
+
+            let id = self.env.resolve(&goal.id());
+            match Builtin::lookup(&id) {
+                Some(Builtin::Call) if is_last => {
+                    if allocate {
+                        compiler.code.push(Instr::Deallocate);
+                    }
+                    compiler.code.push(Instr::ExecuteMeta(id.arity));
+                    ends_with_jump = true;
+                }
+                Some(Builtin::Call) => compiler.code.push(Instr::CallMeta(id.arity)),
+                Some(builtin) => match Op::lookup(builtin) {
+                    Some(op) => compiler.code.push(Instr::Builtin(op)),
+                    None => Err(ErrorKind::UnsupportedByMachine(id))?,
+                },
+                None => {
+                    let pred_idx = self.predicate(id);
+                    if is_last {
+                        if allocate {
+                            compiler.code.push(Instr::Deallocate);
+                        }
+                        compiler.code.push(Instr::Execute(pred_idx));
+                        ends_with_jump = true;
+                    } else {
+                        compiler.code.push(Instr::Call(pred_idx));
+                    }
+                }
+            }
+        }
+        if !ends_with_jump {
+            if allocate {
+                compiler.code.push(Instr::Deallocate);
+            }
+            compiler.code.push(Instr::Proceed);
+        }
+
+        self.num_regs = self.num_regs.max(compiler.next_x);
+        self.code.append(&mut compiler.code);
+        Ok(())
+    }
+
+    fn deref(&self, mut cell: Cell) -> Cell {
+        while let Cell::Ref(addr) = cell {
+            let next = self.heap[addr];
+            if next == cell {
+                break;
+            }
+            cell = next;
+        }
+        cell
+    }
+
+    fn get(&self, reg: Reg) -> Cell {
+        match reg {
+            Reg::X(idx) => self.x[idx],
+            Reg::Y(idx) => self.ys[self.frames[self.e].y_base + idx],
+        }
+    }
+
+    fn set(&mut self, reg: Reg, cell: Cell) {
+        match reg {
+            Reg::X(idx) => self.x[idx] = cell,
+            Reg::Y(idx) => {
+                let base = self.frames[self.e].y_base;
+                self.ys[base + idx] = cell;
+            }
+        }
+    }
+
+    fn new_var(&mut self) -> Cell {
+        let cell = Cell::Ref(self.heap.len());
+        self.heap.push(cell);
+        cell
+    }
+
+    fn bind(&mut self, addr: usize, cell: Cell) {
+        self.heap[addr] = cell;
+        if addr < self.hb {
+            self.trail.push(addr);
+        }
+    }
+
+    ///
+    /// Walks the term until `found` holds for one of its cells.
+    ///
+    fn any_cell(&self, cell: Cell, found: impl Fn(Cell) -> bool) -> bool {
+        let mut stack = vec![cell];
+        while let Some(cell) = stack.pop() {
+            let cell = self.deref(cell);
+            if found(cell) {
+                return true;
+            }
+            if let Cell::Str(addr) = cell {
+                if let Cell::Functor(_, arity) = self.heap[addr] {
+                    stack.extend(&self.heap[addr + 1..=addr + arity]);
+                }
+            }
+        }
+        false
+    }
+
+    ///
+    /// Unifies two terms with the occurs check as the interpreter does.
+    /// The bindings are left on failure, and undone by backtracking.
+    ///
+    fn unify(&mut self, lhs: Cell, rhs: Cell) -> bool {
+        let mut stack = vec![(lhs, rhs)];
+        while let Some((lhs, rhs)) = stack.pop() {
+            let lhs = self.deref(lhs);
+            let rhs = self.deref(rhs);
+            if lhs == rhs {
+                continue;
+            }
+            match (lhs, rhs) {
+                // The younger variable is bound to the older one.
+                (Cell::Ref(l), Cell::Ref(r)) => {
+                    if l < r {
+                        self.bind(r, lhs);
+                    } else {
+                        self.bind(l, rhs);
+                    }
+                }
+                (Cell::Ref(addr), other) | (other, Cell::Ref(addr)) => {
+                    if self.any_cell(other, |cell| cell == Cell::Ref(addr)) {
+                        return false;
+                    }
+                    self.bind(addr, other);
+                }
+                (Cell::Str(l), Cell::Str(r)) => {
+                    let Cell::Functor(_, arity) = self.heap[l] else {
+                        unreachable!()
+                    };
+                    if self.heap[l] != self.heap[r] {
+                        return false;
+                    }
+                    for idx in 1..=arity {
+                        stack.push((self.heap[l + idx], self.heap[r + idx]));
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn identical(&self, lhs: Cell, rhs: Cell) -> bool {
+        let mut stack = vec![(lhs, rhs)];
+        while let Some((lhs, rhs)) = stack.pop() {
+            match (self.deref(lhs), self.deref(rhs)) {
+                (Cell::Str(l), Cell::Str(r)) => {
+                    let Cell::Functor(_, arity) = self.heap[l] else {
+                        unreachable!()
+                    };
+                    if self.heap[l] != self.heap[r] {
+                        return false;
+                    }
+                    for idx in 1..=arity {
+                        stack.push((self.heap[l + idx], self.heap[r + idx]));
+                    }
+                }
+                (lhs, rhs) if lhs == rhs => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn run_builtin(&mut self, op: Op) -> bool {
+        let (lhs, rhs) = (self.x[0], self.x[1]);
+        match op {
+            Op::Unify => self.unify(lhs, rhs),
+            Op::NotUnifiable => {
+                // Every binding is trailed to be undone whether or not the terms unify.
+                let hb = std::mem::replace(&mut self.hb, self.heap.len());
+                let trail_len = self.trail.len();
+                let unifiable = self.unify(lhs, rhs);
+                self.undo_trail(trail_len);
+                self.hb = hb;
+                !unifiable
+            }
+            Op::Identical => self.identical(lhs, rhs),
+            Op::NotIdentical => !self.identical(lhs, rhs),
+        }
+    }
+
+    fn undo_trail(&mut self, len: usize) {
+        for addr in self.trail.drain(len..) {
+            self.heap[addr] = Cell::Ref(addr);
+        }
+    }
+
+    ///
+    /// Runs `call/N` with the goal term in the first register and the extra arguments in the others.
+    ///
+    fn call_meta(&mut self, arity: usize, is_last: bool) -> Result<bool, ErrorKind> {
+        let (name, mut args) = match self.deref(self.x[0]) {
+            Cell::Con(name) => (name, Vec::new()),
+            Cell::Str(addr) => {
+                let Cell::Functor(name, num_args) = self.heap[addr] else {
+                    unreachable!()
+                };
+                (name, self.heap[addr + 1..=addr + num_args].to_vec())
+            }
+            _ => {
+                let id = PredicateId::new("call".to_string(), arity);
+                return Err(ErrorKind::NotInstantiated(id));
+            }
+        };
+        args.extend_from_slice(&self.x[1..arity]);
+
+        let id = PredicateId::new(self.symbols[name as usize].clone(), args.len());
+        let id = self.env.resolve(&id);
+        let entry = match Builtin::lookup(&id) {
+            Some(Builtin::Call) => None,
+            Some(builtin) => {
+                let op = Op::lookup(builtin).ok_or(ErrorKind::UnsupportedByMachine(id.clone()))?;
+                self.x[..args.len()].copy_from_slice(&args);
+                let succeeded = self.run_builtin(op);
+                if succeeded && is_last {
+                    self.p = self.cp;
+                }
+                return Ok(succeeded);
+            }
+            None => {
+                let idx = self.predicate(id);
+                self.compile_pending()?;
+                self.entries[idx]
+            }
+        };
+
+        if self.x.len() < args.len() {
+            self.x.resize(args.len(), Cell::Ref(0));
+        }
+        self.x[..args.len()].copy_from_slice(&args);
+        match entry {
+            // `call(call, ...)` unwraps the goal again.
+            None => self.call_meta(args.len(), is_last),
+            Some(entry) => {
+                if !is_last {
+                    self.cp = self.p;
+                }
+                self.b0 = self.choices.len();
+                self.p = entry;
+                Ok(true)
+            }
+        }
+    }
+
+    ///
+    /// Resumes from the last choice point. Returns false if there is none.
+    ///
+    fn backtrack(&mut self) -> bool {
+        let Some(choice) = self.choices.last() else {
+            return false;
+        };
+        let saved = &self.saved[choice.args_base..choice.args_base + choice.arity];
+        self.x[..choice.arity].copy_from_slice(saved);
+        self.e = choice.e;
+        self.cp = choice.cp;
+        self.b0 = choice.b0;
+        self.p = choice.next;
+        let (trail_len, heap_len) = (choice.trail_len, choice.heap_len);
+        let (frames_len, ys_len) = (choice.frames_len, choice.ys_len);
+
+        self.undo_trail(trail_len);
+        self.heap.truncate(heap_len);
+        self.frames.truncate(frames_len);
+        self.ys.truncate(ys_len);
+        self.hb = heap_len;
+        self.guards.clear();
+        true
+    }
+
+    fn cut(&mut self, len: usize) {
+        if let Some(choice) = self.choices.get(len) {
+            self.saved.truncate(choice.args_base);
+            self.choices.truncate(len);
+            self.hb = self.choices.last().map_or(0, |choice| choice.heap_len);
+        }
+    }
+
+    ///
+    /// Runs the instructions until the query succeeds or no choice point remains.
+    ///
+    fn run(&mut self) -> Result<bool, ErrorKind> {
+        loop {
+            let instr = self.code[self.p];
+            self.p += 1;
+            let succeeded = match instr {
+                Instr::GetVariable(reg, idx) => {
+                    self.set(reg, self.x[idx]);
+                    true
+                }
+                Instr::GetValue(reg, idx) => self.unify(self.get(reg), self.x[idx]),
+                Instr::GetConstant(name, idx) => match self.deref(self.x[idx]) {
+                    Cell::Ref(addr) => {
+                        self.bind(addr, Cell::Con(name));
+                        true
+                    }
+                    cell => cell == Cell::Con(name),
+                },
+                Instr::GetStructure(name, arity, idx) => match self.deref(self.x[idx]) {
+                    Cell::Ref(addr) => {
+                        let functor = self.heap.len();
+                        self.heap.push(Cell::Functor(name, arity));
+                        self.bind(addr, Cell::Str(functor));
+                        // A variable older than the structure being built starts another one.
+                        if self.guards.first().is_none_or(|root| addr < *root) {
+                            self.guards.clear();
+                        }
+                        self.guards.push(functor);
+                        self.write_mode = true;
+                        true
+                    }
+                    Cell::Str(addr) if self.heap[addr] == Cell::Functor(name, arity) => {
+                        self.s = addr + 1;
+                        self.write_mode = false;
+                        true
+                    }
+                    _ => false,
+                },
+                Instr::UnifyVariable(reg) => {
+                    let cell = if self.write_mode {
+                        self.new_var()
+                    } else {
+                        self.s += 1;
+                        self.heap[self.s - 1]
+                    };
+                    self.set(reg, cell);
+                    true
+                }
+                Instr::UnifyValue(reg) => {
+                    let cell = self.get(reg);
+                    if self.write_mode {
+                        let guards = &self.guards;
+                        let cyclic = !guards.is_empty()
+                            && self.any_cell(
+                                cell,
+                                |cell| matches!(cell, Cell::Str(addr) if guards.contains(&addr)),
+                            );
+                        self.heap.push(cell);
+                        !cyclic
+                    } else {
+                        self.s += 1;
+                        self.unify(cell, Cell::Ref(self.s - 1))
+                    }
+                }
+                Instr::UnifyConstant(name) => {
+                    if self.write_mode {
+                        self.heap.push(Cell::Con(name));
+                        true
+                    } else {
+                        self.s += 1;
+                        match self.deref(Cell::Ref(self.s - 1)) {
+                            Cell::Ref(addr) => {
+                                self.bind(addr, Cell::Con(name));
+                                true
+                            }
+                            cell => cell == Cell::Con(name),
+                        }
+                    }
+                }
+                Instr::PutVariable(reg, idx) => {
+                    let cell = self.new_var();
+                    self.set(reg, cell);
+                    self.x[idx] = cell;
+                    true
+                }
+                Instr::PutValue(reg, idx) => {
+                    self.x[idx] = self.get(reg);
+                    true
+                }
+                Instr::PutConstant(name, idx) => {
+                    self.x[idx] = Cell::Con(name);
+                    true
+                }
+                Instr::PutStructure(name, arity, idx) => {
+                    self.x[idx] = Cell::Str(self.heap.len());
+                    self.heap.push(Cell::Functor(name, arity));
+                    true
+                }
+                Instr::SetVariable(reg) => {
+                    let cell = self.new_var();
+                    self.set(reg, cell);
+                    true
+                }
+                Instr::SetValue(reg) => {
+                    self.heap.push(self.get(reg));
+                    true
+                }
+                Instr::SetConstant(name) => {
+                    self.heap.push(Cell::Con(name));
+                    true
+                }
+                Instr::Allocate(size) => {
+                    self.frames.push(Frame {
+                        ce: self.e,
+                        cp: self.cp,
+                        b0: self.b0,
+                        y_base: self.ys.len(),
+                    });
+                    self.ys.resize(self.ys.len() + size, Cell::Ref(0));
+                    self.e = self.frames.len() - 1;
+                    true
+                }
+                Instr::Deallocate => {
+                    let frame = &self.frames[self.e];
+                    let (ce, cp, y_base) = (frame.ce, frame.cp, frame.y_base);
+                    // The frame is freed unless a choice point may return to it.
+                    let protected = self.choices.last().map_or(0, |choice| choice.frames_len);
+                    if self.e + 1 == self.frames.len() && self.e >= protected {
+                        self.frames.pop();
+                        self.ys.truncate(y_base);
+                    }
+                    self.cp = cp;
+                    self.e = ce;
+                    true
+                }
+                Instr::Call(idx) => {
+                    self.cp = self.p;
+                    self.b0 = self.choices.len();
+                    self.guards.clear();
+                    self.p = self.entries[idx].unwrap();
+                    true
+                }
+                Instr::Execute(idx) => {
+                    self.b0 = self.choices.len();
+                    self.guards.clear();
+                    self.p = self.entries[idx].unwrap();
+                    true
+                }
+                Instr::CallMeta(arity) | Instr::ExecuteMeta(arity) => {
+                    self.guards.clear();
+                    self.call_meta(arity, matches!(instr, Instr::ExecuteMeta(_)))?
+                }
+                Instr::Builtin(op) => {
+                    self.guards.clear();
+                    self.run_builtin(op)
+                }
+                Instr::Proceed => {
+                    self.guards.clear();
+                    self.p = self.cp;
+                    true
+                }
+                Instr::TryMeElse(next, arity) => {
+                    self.choices.push(Choice {
+                        arity,
+                        args_base: self.saved.len(),
+                        e: self.e,
+                        cp: self.cp,
+                        b0: self.b0,
+                        next,
+                        trail_len: self.trail.len(),
+                        heap_len: self.heap.len(),
+                        frames_len: self.frames.len(),
+                        ys_len: self.ys.len(),
+                    });
+                    self.saved.extend_from_slice(&self.x[..arity]);
+                    self.hb = self.heap.len();
+                    true
+                }
+                Instr::RetryMeElse(next) => {
+                    self.choices.last_mut().unwrap().next = next;
+                    true
+                }
+                Instr::TrustMe => {
+                    let len = self.choices.len() - 1;
+                    self.cut(len);
+                    true
+                }
+                Instr::Cut => {
+                    self.cut(self.frames[self.e].b0);
+                    true
+                }
+                Instr::Fail => false,
+                Instr::Undefined(idx) => {
+                    let id = &self.preds[idx];
+                    match self.env.unknown() {
+                        UnknownMode::Fail => {}
+                        UnknownMode::Warning => {
+                            if self.reported.insert(id.clone()) {
+                                self.warnings
+                                    .push(WarningKind::UnknownPredicate(id.clone()));
+                            }
+                        }
+                        UnknownMode::Error => Err(ErrorKind::UnknownPredicate(id.clone()))?,
+                    }
+                    false
+                }
+                Instr::Halt => return Ok(true),
+            };
+            if !succeeded && !self.backtrack() {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn answer(&self) -> VarSubstitution {
+        let mut subst = VarSubstitution::new();
+        for (idx, (_, id)) in self.query_vars.iter().enumerate() {
+            let cell = self.deref(Cell::Ref(idx));
+            if cell != Cell::Ref(idx) {
+                subst.insert(*id, self.decode(cell));
+            }
+        }
+        subst
+    }
+
+    ///
+    /// Reads a term back from the heap. Variables other than the ones of the query
+    /// are named after their addresses.
+    ///
+    fn decode(&self, cell: Cell) -> Expr {
+        match self.deref(cell) {
+            Cell::Con(name) => AtomExpr::new(self.symbols[name as usize].clone(), vec![]),
+            Cell::Str(addr) => {
+                let Cell::Functor(name, arity) = self.heap[addr] else {
+                    unreachable!()
+                };
+                let arguments = (1..=arity)
+                    .map(|idx| self.decode(self.heap[addr + idx]))
+                    .collect();
+                AtomExpr::new(self.symbols[name as usize].clone(), arguments)
+            }
+            Cell::Ref(addr) => match self.query_vars.get(addr) {
+                Some((name, id)) => Expr::Var(VarExpr {
+                    name: name.clone(),
+                    id: Some(*id),
+                }),
+                None => Expr::Var(VarExpr {
+                    name: format!("_{}", addr).into(),
+                    id: Some((self.query_vars.len() + addr) as VarID),
+                }),
+            },
+            Cell::Functor(_, _) => unreachable!(),
+        }
+    }
+}
+
+impl Solver for Machine<'_> {
+    fn next(&mut self) -> Result<Option<VarSubstitution>, ErrorKind> {
+        Machine::next(self)
+    }
+
+    fn take_warnings(&mut self) -> Vec<WarningKind> {
+        Machine::take_warnings(self)
+    }
+
+    ///
+    /// The machine has no `dif/2`, so no goal is ever suspended.
+    ///
+    fn residual_goals(&self) -> Vec<PredicateObj> {
+        Vec::new()
+    }
+}
+
+///
+/// Emits the instructions of a clause, assigning registers to its variables.
+///
+struct ClauseCompiler {
+    /// The register of each variable and whether it has been seen.
+    vars: HashMap<Symbol, (Reg, bool)>,
+    next_x: usize,
+    code: Vec<Instr>,
+}
+
+impl ClauseCompiler {
+    ///
+    /// Returns the register of the variable and whether this is its first occurrence.
+    /// A temporary variable is given a new register above the argument registers.
+    ///
+    fn var(&mut self, name: &Symbol) -> (Reg, bool) {
+        match self.vars.get_mut(name) {
+            Some((reg, seen)) => {
+                let first = !*seen;
+                *seen = true;
+                (*reg, first)
+            }
+            None => {
+                let reg = Reg::X(self.temp());
+                self.vars.insert(name.clone(), (reg, true));
+                (reg, true)
+            }
+        }
+    }
+
+    fn temp(&mut self) -> usize {
+        self.next_x += 1;
+        self.next_x - 1
+    }
+
+    fn get_arguments(&mut self, machine: &mut Machine, head: &PredicateObj) {
+        for (idx, arg) in head.arguments.iter().enumerate() {
+            match arg {
+                Expr::Var(var) => {
+                    let (reg, first) = self.var(&var.name);
+                    self.code.push(if first {
+                        Instr::GetVariable(reg, idx)
+                    } else {
+                        Instr::GetValue(reg, idx)
+                    });
+                }
+                Expr::Atom(atom) if atom.arguments.is_empty() => {
+                    let name = machine.symbol(&atom.name);
+                    self.code.push(Instr::GetConstant(name, idx));
+                }
+                Expr::Atom(atom) => self.get_structure(machine, atom, idx),
+            }
+        }
+    }
+
+    ///
+    /// Matches a structure in the register, and then its nested structures
+    /// through the temporary registers which `UnifyVariable` fills.
+    ///
+    fn get_structure(&mut self, machine: &mut Machine, atom: &AtomExpr, idx: usize) {
+        let mut queue = vec![(atom, idx)];
+        while let Some((atom, idx)) = queue.pop() {
+            let name = machine.symbol(&atom.name);
+            self.code
+                .push(Instr::GetStructure(name, atom.arguments.len(), idx));
+            for arg in atom.arguments.iter() {
+                match arg {
+                    Expr::Var(var) => {
+                        let (reg, first) = self.var(&var.name);
+                        self.code.push(if first {
+                            Instr::UnifyVariable(reg)
+                        } else {
+                            Instr::UnifyValue(reg)
+                        });
+                    }
+                    Expr::Atom(nested) if nested.arguments.is_empty() => {
+                        let name = machine.symbol(&nested.name);
+                        self.code.push(Instr::UnifyConstant(name));
+                    }
+                    Expr::Atom(nested) => {
+                        let temp = self.temp();
+                        self.code.push(Instr::UnifyVariable(Reg::X(temp)));
+                        queue.push((nested, temp));
+                    }
+                }
+            }
+        }
+    }
+
+    fn put_arguments(&mut self, machine: &mut Machine, goal: &PredicateObj) {
+        for (idx, arg) in goal.arguments.iter().enumerate() {
+            match arg {
+                Expr::Var(var) => {
+                    let (reg, first) = self.var(&var.name);
+                    self.code.push(if first {
+                        Instr::PutVariable(reg, idx)
+                    } else {
+                        Instr::PutValue(reg, idx)
+                    });
+                }
+                Expr::Atom(atom) if atom.arguments.is_empty() => {
+                    let name = machine.symbol(&atom.name);
+                    self.code.push(Instr::PutConstant(name, idx));
+                }
+                Expr::Atom(atom) => self.put_structure(machine, atom, idx),
+            }
+        }
+    }
+
+    ///
+    /// Builds a structure into the register, building the nested ones first.
+    ///
+    fn put_structure(&mut self, machine: &mut Machine, atom: &AtomExpr, idx: usize) {
+        let nested = atom
+            .arguments
+            .iter()
+            .map(|arg| match arg {
+                Expr::Atom(nested) if !nested.arguments.is_empty() => {
+                    let temp = self.temp();
+                    self.put_structure(machine, nested, temp);
+                    Some(temp)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let name = machine.symbol(&atom.name);
+        self.code
+            .push(Instr::PutStructure(name, atom.arguments.len(), idx));
+        for (arg, nested) in atom.arguments.iter().zip(nested) {
+            match (arg, nested) {
+                (_, Some(temp)) => self.code.push(Instr::SetValue(Reg::X(temp))),
+                (Expr::Var(var), None) => {
+                    let (reg, first) = self.var(&var.name);
+                    self.code.push(if first {
+                        Instr::SetVariable(reg)
+                    } else {
+                        Instr::SetValue(reg)
+                    });
+                }
+                (Expr::Atom(atom), None) => {
+                    let name = machine.symbol(&atom.name);
+                    self.code.push(Instr::SetConstant(name));
+                }
+            }
+        }
+    }
+}
+
+fn flatten_and(item: BodyItem, flat: &mut Vec<BodyItem>) {
+    match item {
+        BodyItem::Goal(Goal::And(goals)) => {
+            for goal in goals {
+                flatten_and(BodyItem::Goal(goal), flat);
+            }
+        }
+        item => flat.push(item),
+    }
+}
+
+///
+/// Collects the names of the variables without duplicates.
+///
+fn expr_vars(expr: &Expr, names: &mut Vec<Symbol>) {
+    match expr {
+        Expr::Atom(atom) => atom.arguments.iter().for_each(|arg| expr_vars(arg, names)),
+        Expr::Var(var) => {
+            if !names.contains(&var.name) {
+                names.push(var.name.clone());
+            }
+        }
+    }
+}
+
+fn goal_vars(goal: &Goal, names: &mut Vec<Symbol>) {
+    for pred_obj in goal.predicates() {
+        pred_obj
+            .arguments
+            .iter()
+            .for_each(|arg| expr_vars(arg, names));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ast::Statement, env::run_program, evaluation::SolutionGenerator, prelude::load_prelude,
+        testing::rename_vars,
+    };
+
+    ///
+    /// Formats the answers with the variables renamed in the order of their appearance,
+    /// and sorts them since the engines search in different orders.
+    ///
+    fn canonical(
+        solver: &mut dyn Solver,
+        mut name_table: Vec<(String, VarID)>,
+    ) -> Result<Vec<String>, ErrorKind> {
+        name_table.sort();
+        let mut answers = Vec::new();
+        while let Some(solution) = solver.next()? {
+            let mut vars = Vec::new();
+            let answer = name_table
+                .iter()
+                .map(|(name, id)| {
+                    let value = solution.get(*id).cloned().unwrap_or(Expr::Var(VarExpr {
+                        name: name.into(),
+                        id: Some(*id),
+                    }));
+                    format!("{} = {}", name, rename_vars(&value, &mut vars))
+                })
+                .collect::<Vec<_>>();
+            answers.push(answer.join(", "));
+        }
+        answers.sort();
+        Ok(answers)
+    }
+
+    ///
+    /// Runs every query of the program on both engines, and checks that they give the same answers.
+    /// Returns the answers of the machine.
+    ///
+    fn differential(program: &str) -> Vec<Vec<String>> {
+        let mut env = Environment::new();
+        load_prelude(&mut env).unwrap();
+        let mut results = Vec::new();
+        run_program(&mut env, program, |env, module, stmt| {
+            let Statement::Query(mut stmt) = stmt else {
+                return;
+            };
+            env.qualify(module, &mut stmt.query).unwrap();
+            let mut query = stmt.query.clone();
+            let (mut machine, name_table) = Machine::new(&mut query, env).unwrap();
+            let expected = canonical(&mut machine, name_table).unwrap();
+
+            let mut query = stmt.query.clone();
+            let (mut solution_gen, name_table) = SolutionGenerator::new(&mut query, env).unwrap();
+            let actual = canonical(&mut solution_gen, name_table).unwrap();
+            assert_eq!(expected, actual, "{}", stmt.query);
+            results.push(expected);
+        });
+        results
+    }
+
+    #[test]
+    fn peano_test() {
+        let results = differential(
+            "?add($x, $y, s(s(s(z)))).
+            ?mul(s(s(z)), s(s(s(z))), $z).
+            ?sub(s(s(s(z))), s(z), $z).
+            ?max(s(z), s(s(z)), $m).
+            ?le($x, s(s(z))).
+            ?add(s(z), $x, z).",
+        );
+        assert_eq!(results[0].len(), 4);
+        assert_eq!(results[1], vec!["z = s(s(s(s(s(s(z))))))"]);
+        assert!(results[5].is_empty());
+    }
+
+    #[test]
+    fn list_test() {
+        let results = differential(
+            "?append($x, $y, [a, b, c]).
+            ?append([a | $x], [c], [a, b, c]).
+            ?member($x, [a, b, a]).
+            ?length([a, b, c], $n).
+            ?reverse([a, b, c], $r).
+            ?last([a, b, c], $x).
+            ?select($x, [a, b, c], $r).
+            ?maplist(add(s(z)), [z, s(z)], $ys).
+            ?maplist(add, [z, s(z)], [s(z), s(z)], $zs).
+            ?foldl(add, [s(z), s(s(z))], z, $n).
+            ?include(le(s(z)), [z, s(z), s(s(z))], $l).
+            ?exclude(le(s(z)), [z, s(z), s(s(z))], $l).
+            ?call(member, $x, [a, b]).
+            ?call(call, member($x), [c]).",
+        );
+        assert_eq!(results[0].len(), 4);
+        assert_eq!(results[2].len(), 3);
+        assert_eq!(results[10], vec!["l = [s(z), s(s(z))]"]);
+    }
+
+    #[test]
+    fn control_test() {
+        let results = differential(
+            "color(red). color(green). color(blue).
+            warm($x) <- ($x = red ; $x = orange).
+            pick($x, $y) <- (color($x) -> $y = some ; $y = none).
+            either($x, $y) <- (color($x), $y = c ; $x = $y).
+            guard($x) <- ($x == a -> fail_now ; true).
+            branch($x) <- (true -> member($x, [a, b]) ; $x = c).
+            true.
+            ?warm($x).
+            ?pick($x, $y).
+            ?pick(pink, $y).
+            ?either($x, $y).
+            ?guard(b).
+            ?guard($x).
+            ?$x = f($y).
+            ?f($x, b) \\= f(a, $x).
+            ?f($x) \\= f(a).
+            ?$x == $x.
+            ?$x \\== $y.
+            ?f($x, $y) == f($x, $y).
+            ?branch($x).",
+        );
+        assert_eq!(results[0], vec!["x = orange", "x = red"]);
+        assert_eq!(results[1], vec!["x = red, y = some"]);
+        assert_eq!(results[3].len(), 4);
+        assert!(results[8].is_empty());
+        assert_eq!(results[12], vec!["x = a", "x = b"]);
+    }
+
+    #[test]
+    fn occurs_check_test() {
+        let results = differential(
+            "eq($x, $x).
+            p($x, f($x)).
+            q(f($x), $x).
+            r($x, f($a), $a, g($x)).
+            s($b, f(g($a), h($b))).
+            ?eq($x, f($x)).
+            ?p($y, $y).
+            ?q($y, $y).
+            ?r($x, $x, $w, $w).
+            ?s($a, f($a, $b)).
+            ?$x = f($x).",
+        );
+        assert!(results[..4].iter().all(|answers| answers.is_empty()));
+        // A term shared with another argument is not cyclic.
+        assert_eq!(results[4], vec!["a = g($_0), b = h(g($_0))"]);
+        assert!(results[5].is_empty());
+    }
+
+    #[test]
+    fn module_test() {
+        let results = differential(
+            "#module shapes.
+            #export area/2.
+            helper($x, $y) <- mul($x, $x, $y).
+            area(square($x), $a) <- helper($x, $a).
+            ?area(square(s(s(z))), $a).
+            ?call(helper, s(z), $a).",
+        );
+        assert_eq!(results[0], vec!["a = s(s(s(s(z))))"]);
+    }
+
+    #[test]
+    fn unsupported_test() {
+        let mut env = Environment::new();
+        let mut query = PredicateObj::new(
+            "findall".to_string(),
+            vec![
+                VarExpr::new("x".to_string()),
+                AtomExpr::new("p".to_string(), vec![VarExpr::new("x".to_string())]),
+                VarExpr::new("l".to_string()),
+            ],
+        );
+        let err = Machine::new(&mut query, &env).err().unwrap();
+        assert_eq!(err.code(), "E0019");
+
+        // An undefined predicate follows the setting of the environment.
+        env.set_unknown(UnknownMode::Error);
+        let mut query = PredicateObj::new("nope".to_string(), vec![]);
+        let (mut machine, _) = Machine::new(&mut query, &env).unwrap();
+        assert_eq!(machine.next().err().unwrap().code(), "E0007");
+    }
+}